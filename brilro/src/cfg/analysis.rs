@@ -1,6 +1,14 @@
-use std::{collections::BTreeMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Display,
+};
 
-use crate::parser::ast::*;
+use crate::{cfg::lvn::is_terminator, parser::ast::*};
+
+use super::data_flow::{DefinedVariables, ShimmedCfg};
+
+/// An instruction identified by its block start and index in the block.
+pub type Position = (usize, usize);
 
 #[derive(Debug, Clone)]
 pub struct BasicBlock {
@@ -214,6 +222,285 @@ impl Cfg {
         fun
     }
 
+    /// Returns the starts of every block reachable from the entry.
+    pub fn reachable(&self) -> HashSet<usize> {
+        let mut reachable = HashSet::new();
+        let mut stack: Vec<usize> = self.blocks.first().map(|b| b.start).into_iter().collect();
+        while let Some(b) = stack.pop() {
+            if reachable.insert(b) {
+                stack.extend(&self.block(b).flows_to);
+            }
+        }
+        reachable
+    }
+
+    /// A copy with an `undef` for every parameter at the top of the entry block.
+    ///
+    /// Reaching definitions has nothing standing for the value a parameter is passed, so without
+    /// these a definition on only some of the paths from the entry looks like the only one.
+    pub(super) fn with_param_defs(&self) -> Self {
+        let mut cfg = self.clone();
+        let params = self
+            .original_function
+            .args
+            .iter()
+            .map(|a| Instruction::Value {
+                op: ValueOp::Undef,
+                dest: a.name.clone(),
+                ty: a.ty,
+                args: vec![],
+                funcs: vec![],
+                labels: vec![],
+                span: None,
+            });
+        if let Some(entry) = cfg.blocks.first_mut() {
+            let idx = usize::from(matches!(
+                entry.instrs.first(),
+                Some(Instruction::Label { .. })
+            ));
+            entry.instrs.splice(idx..idx, params);
+        }
+        cfg
+    }
+
+    /// The variables assigned on every path from the entry to the end of `block`, parameters
+    /// included.
+    pub(super) fn defined_after(&self, block: usize) -> BTreeSet<String> {
+        let mut solved: ShimmedCfg<DefinedVariables> = ShimmedCfg::from_cfg(self);
+        solved.solve_bit_vector();
+        let mut defined = solved.outset(block).clone().unwrap_or_default();
+        defined.extend(self.original_function.args.iter().map(|a| a.name.clone()));
+        defined
+    }
+
+    /// Returns true if `var` may be read on a path from the start of one of `blocks` before being
+    /// redefined.
+    pub(super) fn live_at(&self, blocks: impl IntoIterator<Item = usize>, var: &str) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack: Vec<usize> = blocks.into_iter().collect();
+        while let Some(b) = stack.pop() {
+            if !seen.insert(b) {
+                continue;
+            }
+            let block = self.block(b);
+            let mut killed = false;
+            for insn in &block.instrs {
+                if uses(insn).iter().any(|u| u == var) {
+                    return true;
+                }
+                if dest(insn).is_some_and(|d| d == var) {
+                    killed = true;
+                    break;
+                }
+            }
+            if !killed {
+                stack.extend(&block.flows_to);
+            }
+        }
+        false
+    }
+
+    /// Recomputes the `pred` of every block from the `flows_to` edges.
+    ///
+    /// Should be called after editing the edges of the CFG.
+    pub(super) fn recompute_preds(&mut self) {
+        let preds: Vec<Vec<usize>> = self
+            .blocks
+            .iter()
+            .map(|block| {
+                self.blocks
+                    .iter()
+                    .filter(|b| b.flows_to.contains(&block.start))
+                    .map(|b| b.start)
+                    .collect()
+            })
+            .collect();
+        for (block, pred) in self.blocks.iter_mut().zip(preds) {
+            block.pred = pred;
+        }
+    }
+
+    /// Returns a label not yet used in the function, based on `base`.
+    pub(super) fn fresh_label(&self, base: &str) -> String {
+        let taken: HashSet<&str> = self
+            .blocks
+            .iter()
+            .filter_map(|b| b.name.as_deref())
+            .collect();
+        fresh_name(base, |label| taken.contains(label))
+    }
+
+    /// Returns a variable name not yet used in the function, based on `base`.
+    pub(super) fn fresh_var(&self, base: &str) -> String {
+        let taken: HashSet<&str> = self
+            .original_function
+            .args
+            .iter()
+            .map(|a| a.name.as_str())
+            .chain(
+                self.blocks
+                    .iter()
+                    .flat_map(|b| &b.instrs)
+                    .flat_map(mentioned)
+                    .map(String::as_str),
+            )
+            .collect();
+        fresh_name(base, |var| taken.contains(var))
+    }
+
+    /// Returns the label of a block, giving it a fresh one if it has none.
+    pub(super) fn label(&mut self, start: usize) -> String {
+        if let Some(name) = &self.block(start).name {
+            return name.clone();
+        }
+        let label = self.fresh_label(&format!("b{start}"));
+        let block = self.block_mut(start);
+        block.instrs.insert(
+            0,
+            Instruction::Label {
+                label: label.clone(),
+                span: None,
+            },
+        );
+        block.name = Some(label.clone());
+        label
+    }
+
+    /// Inserts an empty block named `label` which jumps to `target`, laid out directly before
+    /// the block `before`. Returns the start of the new block.
+    ///
+    /// Every block starting at or after `before` is renumbered, and a block which used to fall
+    /// through into `before` gets an explicit `jmp`. Nothing jumps to the new block yet.
+    pub(super) fn insert_block(&mut self, before: usize, label: String, target: usize) -> usize {
+        let idx = self.blocks.iter().position(|b| b.start == before).unwrap();
+        if idx > 0 {
+            let prev = &self.blocks[idx - 1];
+            let falls_through =
+                prev.flows_to == [before] && !prev.instrs.last().is_some_and(is_terminator);
+            if falls_through {
+                let prev = prev.start;
+                let before_label = self.label(before);
+                self.block_mut(prev).instrs.push(jump(before_label));
+            }
+        }
+
+        let target_label = self.label(target);
+        let shift = |s: &mut usize| {
+            if *s >= before {
+                *s += 1;
+            }
+        };
+        for block in &mut self.blocks {
+            shift(&mut block.start);
+            block.flows_to.iter_mut().for_each(shift);
+            block.pred.iter_mut().for_each(shift);
+        }
+        let mut target = target;
+        shift(&mut target);
+
+        self.blocks.insert(
+            idx,
+            BasicBlock {
+                start: before,
+                name: Some(label.clone()),
+                instrs: vec![Instruction::Label { label, span: None }, jump(target_label)],
+                flows_to: vec![target],
+                pred: vec![],
+            },
+        );
+        self.recompute_preds();
+        before
+    }
+
+    /// Redirects the edge from `from` to `old` so it goes to `new` instead.
+    pub(super) fn retarget(&mut self, from: usize, old: usize, new: usize) {
+        let old_label = self.block(old).name.clone();
+        let new_label = self.label(new);
+        let block = self.block_mut(from);
+        for succ in &mut block.flows_to {
+            if *succ == old {
+                *succ = new;
+            }
+        }
+        match block.instrs.last_mut() {
+            Some(Instruction::Effect {
+                op: EffectOp::Jmp | EffectOp::Br,
+                labels,
+                ..
+            }) => {
+                for label in labels.iter_mut() {
+                    if old_label.as_ref() == Some(label) {
+                        *label = new_label.clone();
+                    }
+                }
+            }
+            // The block used to fall through into `old`.
+            _ => block.instrs.push(jump(new_label)),
+        }
+        self.recompute_preds();
+    }
+
+    /// Removes the blocks with the given labels which do nothing but jump somewhere else, like
+    /// preheaders nothing was hoisted into, sending their predecessors straight to the target.
+    /// Such an entry block is kept but falls through instead.
+    pub(super) fn remove_forwarding_blocks(&mut self, labels: &[String]) {
+        let listed = |b: &BasicBlock| b.name.as_ref().is_some_and(|name| labels.contains(name));
+        while let Some(b) = self.blocks.iter().find(|b| {
+            b.start != 0
+                && listed(b)
+                && b.flows_to != [b.start]
+                && matches!(
+                    &b.instrs[..],
+                    [
+                        Instruction::Label { .. },
+                        Instruction::Effect {
+                            op: EffectOp::Jmp,
+                            ..
+                        }
+                    ]
+                )
+        }) {
+            let (start, target) = (b.start, b.flows_to[0]);
+            let at = self.blocks.iter().position(|b| b.start == start).unwrap();
+            let before = &self.blocks[at - 1];
+            let falls_in = b.pred.contains(&before.start)
+                && !matches!(
+                    before.instrs.last(),
+                    Some(Instruction::Effect {
+                        op: EffectOp::Br,
+                        ..
+                    })
+                );
+            for p in b.pred.clone() {
+                self.retarget(p, start, target);
+            }
+            self.blocks.retain(|b| b.start != start);
+            self.recompute_preds();
+            // The block before only jumped to the removed one because it was put in the way, so
+            // it can fall into the target again if that comes next.
+            if falls_in && self.blocks.get(at).is_some_and(|b| b.start == target) {
+                self.blocks[at - 1].instrs.pop();
+            }
+        }
+        // The entry has to stay, but it can fall into its target rather than jump there.
+        if let [entry, next, ..] = &self.blocks[..]
+            && listed(entry)
+            && entry.flows_to == [next.start]
+            && matches!(
+                &entry.instrs[..],
+                [
+                    Instruction::Label { .. },
+                    Instruction::Effect {
+                        op: EffectOp::Jmp,
+                        ..
+                    }
+                ]
+            )
+        {
+            self.blocks[0].instrs.pop();
+        }
+    }
+
     /// Returns the block given the block start.
     ///
     /// Panics if start isn't found.
@@ -228,3 +515,105 @@ impl Cfg {
         self.blocks.iter_mut().find(|p| p.start == start).unwrap()
     }
 }
+
+/// A `jmp` to `label`.
+pub(super) fn jump(label: String) -> Instruction {
+    Instruction::Effect {
+        op: EffectOp::Jmp,
+        args: vec![],
+        funcs: vec![],
+        labels: vec![label],
+        span: None,
+    }
+}
+
+/// A copy of `arg` into `dest`.
+pub(super) fn id(dest: String, ty: Type, arg: String, span: Option<Span>) -> Instruction {
+    Instruction::Value {
+        op: ValueOp::Id,
+        dest,
+        ty,
+        args: vec![arg],
+        funcs: vec![],
+        labels: vec![],
+        span,
+    }
+}
+
+/// `base`, or `base` with the first numeric suffix which isn't `taken`.
+pub(super) fn fresh_name(base: &str, taken: impl Fn(&str) -> bool) -> String {
+    let mut name = base.to_string();
+    let mut i = 0;
+    while taken(&name) {
+        i += 1;
+        name = format!("{base}.{i}");
+    }
+    name
+}
+
+/// The variables `insn` reads.
+pub(super) fn uses(insn: &Instruction) -> &[String] {
+    match insn {
+        // The first argument of a `set` names a shadow variable rather than a value.
+        Instruction::Effect {
+            op: EffectOp::Set,
+            args,
+            ..
+        } => &args[1..],
+        Instruction::Value { args, .. } | Instruction::Effect { args, .. } => args,
+        Instruction::Constant { .. } | Instruction::Label { .. } => &[],
+    }
+}
+
+/// The variable `insn` writes, if any.
+pub(super) fn dest(insn: &Instruction) -> Option<&String> {
+    match insn {
+        Instruction::Constant { dest, .. } | Instruction::Value { dest, .. } => Some(dest),
+        Instruction::Effect { .. } | Instruction::Label { .. } => None,
+    }
+}
+
+/// Every variable `insn` mentions, including the shadow variable of a `set`.
+pub(super) fn mentions(insn: &mut Instruction) -> Vec<&mut String> {
+    match insn {
+        Instruction::Constant { dest, .. } => vec![dest],
+        Instruction::Value { dest, args, .. } => std::iter::once(dest).chain(args).collect(),
+        Instruction::Effect { args, .. } => args.iter_mut().collect(),
+        Instruction::Label { .. } => vec![],
+    }
+}
+
+/// Like [`mentions`], for when the names are only read.
+pub(super) fn mentioned(insn: &Instruction) -> Vec<&String> {
+    match insn {
+        Instruction::Constant { dest, .. } => vec![dest],
+        Instruction::Value { dest, args, .. } => std::iter::once(dest).chain(args).collect(),
+        Instruction::Effect { args, .. } => args.iter().collect(),
+        Instruction::Label { .. } => vec![],
+    }
+}
+
+/// Evaluates `op` on constant arguments with the same semantics as brili.
+///
+/// Returns `None` if the operation can't be folded, for example division by zero which must be
+/// left in place to trap at runtime.
+pub(super) fn fold(op: &ValueOp, args: &[Literal]) -> Option<Literal> {
+    use Literal::{Bool, Int};
+    match (op, args) {
+        (ValueOp::Add, [Int(a), Int(b)]) => Some(Int(a.wrapping_add(*b))),
+        (ValueOp::Sub, [Int(a), Int(b)]) => Some(Int(a.wrapping_sub(*b))),
+        (ValueOp::Mul, [Int(a), Int(b)]) => Some(Int(a.wrapping_mul(*b))),
+        (ValueOp::Div, [Int(_), Int(0)]) => None,
+        (ValueOp::Div, [Int(a), Int(b)]) => Some(Int(a.wrapping_div(*b))),
+        (ValueOp::Eq, [a, b]) => Some(Bool(a == b)),
+        (ValueOp::Lt, [Int(a), Int(b)]) => Some(Bool(a < b)),
+        (ValueOp::Gt, [Int(a), Int(b)]) => Some(Bool(a > b)),
+        (ValueOp::Le, [Int(a), Int(b)]) => Some(Bool(a <= b)),
+        (ValueOp::Ge, [Int(a), Int(b)]) => Some(Bool(a >= b)),
+        (ValueOp::Not, [Bool(a)]) => Some(Bool(!a)),
+        (ValueOp::And, [Bool(a), Bool(b)]) => Some(Bool(*a && *b)),
+        (ValueOp::Or, [Bool(a), Bool(b)]) => Some(Bool(*a || *b)),
+        (ValueOp::Id, [a]) => Some(a.clone()),
+        _ => None,
+    }
+}
@@ -0,0 +1,141 @@
+use std::{collections::HashMap, hash::Hash};
+
+const WORD: usize = u64::BITS as usize;
+
+/// A dense set of numbers below a fixed length, one bit each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitSet {
+    len: usize,
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    /// The empty set of numbers below `len`.
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            words: vec![0; len.div_ceil(WORD)],
+        }
+    }
+
+    /// The set of every number below `len`.
+    pub fn full(len: usize) -> Self {
+        let mut set = Self {
+            len,
+            words: vec![u64::MAX; len.div_ceil(WORD)],
+        };
+        if let Some(last) = set.words.last_mut()
+            && !len.is_multiple_of(WORD)
+        {
+            *last = (1 << (len % WORD)) - 1;
+        }
+        set
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|w| *w == 0)
+    }
+
+    pub fn contains(&self, i: usize) -> bool {
+        self.words[i / WORD] & (1 << (i % WORD)) != 0
+    }
+
+    pub fn insert(&mut self, i: usize) {
+        assert!(
+            i < self.len,
+            "{i} is out of range for a set below {}",
+            self.len
+        );
+        self.words[i / WORD] |= 1 << (i % WORD);
+    }
+
+    pub fn remove(&mut self, i: usize) {
+        self.words[i / WORD] &= !(1 << (i % WORD));
+    }
+
+    pub fn clear(&mut self) {
+        self.words.fill(0);
+    }
+
+    /// Adds everything in `other`, which has to have the same length.
+    pub fn union_with(&mut self, other: &Self) {
+        for (a, b) in self.words.iter_mut().zip(&other.words) {
+            *a |= b;
+        }
+    }
+
+    /// Keeps only what is also in `other`, which has to have the same length.
+    pub fn intersect_with(&mut self, other: &Self) {
+        for (a, b) in self.words.iter_mut().zip(&other.words) {
+            *a &= b;
+        }
+    }
+
+    /// Removes everything in `other`, which has to have the same length.
+    pub fn subtract(&mut self, other: &Self) {
+        for (a, b) in self.words.iter_mut().zip(&other.words) {
+            *a &= !b;
+        }
+    }
+
+    /// The numbers in the set in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(w, &word)| {
+            (0..WORD)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| w * WORD + bit)
+        })
+    }
+}
+
+/// Numbers values in the order they are first seen, so they can stand for bits of a [`BitSet`].
+#[derive(Debug, Clone)]
+pub struct Interner<T> {
+    values: Vec<T>,
+    numbers: HashMap<T, usize>,
+}
+
+impl<T> Default for Interner<T> {
+    fn default() -> Self {
+        Self {
+            values: vec![],
+            numbers: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Clone + Eq + Hash> Interner<T> {
+    /// Returns the number of `value`, giving it the next one if it hasn't been seen.
+    pub fn intern(&mut self, value: &T) -> usize {
+        if let Some(&n) = self.numbers.get(value) {
+            return n;
+        }
+        self.values.push(value.clone());
+        self.numbers.insert(value.clone(), self.values.len() - 1);
+        self.values.len() - 1
+    }
+
+    /// Returns the number of `value`, if it has one.
+    pub fn number(&self, value: &T) -> Option<usize> {
+        self.numbers.get(value).copied()
+    }
+
+    /// Returns the value numbered `n`.
+    ///
+    /// Panics if nothing has that number.
+    pub fn value(&self, n: usize) -> &T {
+        &self.values[n]
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::parser::ast::{EffectOp, Instruction, Program, ValueOp};
+
+/// Which functions call which, built from the `funcs` of every call instruction.
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    /// The functions each function in the program calls directly.
+    pub calls: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// State of Tarjan's algorithm while finding strongly connected components.
+struct Tarjan<'a> {
+    graph: &'a CallGraph,
+    index: HashMap<&'a str, usize>,
+    low: HashMap<&'a str, usize>,
+    stack: Vec<&'a str>,
+    sccs: Vec<Vec<String>>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn visit(&mut self, f: &'a str) {
+        let idx = self.index.len();
+        self.index.insert(f, idx);
+        self.low.insert(f, idx);
+        self.stack.push(f);
+        for g in self.graph.callees(f) {
+            if !self.index.contains_key(g.as_str()) {
+                self.visit(g);
+                let low = self.low[f].min(self.low[g.as_str()]);
+                self.low.insert(f, low);
+            } else if self.stack.contains(&g.as_str()) {
+                let low = self.low[f].min(self.index[g.as_str()]);
+                self.low.insert(f, low);
+            }
+        }
+        if self.low[f] == self.index[f] {
+            let mut scc = vec![];
+            while let Some(g) = self.stack.pop() {
+                scc.push(g.to_string());
+                if g == f {
+                    break;
+                }
+            }
+            scc.sort();
+            self.sccs.push(scc);
+        }
+    }
+}
+
+impl CallGraph {
+    pub fn from_program(prog: &Program) -> Self {
+        let calls = prog
+            .functions
+            .iter()
+            .map(|f| {
+                let callees = f
+                    .instrs
+                    .iter()
+                    .filter_map(|insn| match insn {
+                        Instruction::Value {
+                            op: ValueOp::Call,
+                            funcs,
+                            ..
+                        }
+                        | Instruction::Effect {
+                            op: EffectOp::Call,
+                            funcs,
+                            ..
+                        } => funcs.first().cloned(),
+                        _ => None,
+                    })
+                    .collect();
+                (f.name.clone(), callees)
+            })
+            .collect();
+        CallGraph { calls }
+    }
+
+    /// The functions `f` calls directly, ignoring any not defined in the program.
+    pub fn callees<'a>(&'a self, f: &str) -> impl Iterator<Item = &'a String> {
+        self.calls
+            .get(f)
+            .into_iter()
+            .flatten()
+            .filter(|g| self.calls.contains_key(*g))
+    }
+
+    /// The functions calling `f` directly.
+    pub fn callers<'a>(&'a self, f: &'a str) -> impl Iterator<Item = &'a String> {
+        self.calls
+            .iter()
+            .filter(move |(_, callees)| callees.contains(f))
+            .map(|(g, _)| g)
+    }
+
+    /// The functions `f` can end up calling, including `f` itself.
+    pub fn reachable(&self, f: &str) -> BTreeSet<String> {
+        let mut reachable = BTreeSet::new();
+        let mut stack = vec![f.to_string()];
+        while let Some(g) = stack.pop() {
+            if self.calls.contains_key(&g) && reachable.insert(g.clone()) {
+                stack.extend(self.callees(&g).cloned());
+            }
+        }
+        reachable
+    }
+
+    /// The strongly connected components of the graph, each sorted by name. Callees come before
+    /// their callers, unless they are in the same component.
+    pub fn sccs(&self) -> Vec<Vec<String>> {
+        let mut tarjan = Tarjan {
+            graph: self,
+            index: HashMap::new(),
+            low: HashMap::new(),
+            stack: vec![],
+            sccs: vec![],
+        };
+        for f in self.calls.keys() {
+            if !tarjan.index.contains_key(f.as_str()) {
+                tarjan.visit(f);
+            }
+        }
+        tarjan.sccs
+    }
+
+    /// Whether `f` can call itself, directly or through other functions.
+    pub fn is_recursive(&self, f: &str) -> bool {
+        self.callees(f).any(|g| self.reachable(g).contains(f))
+    }
+
+    /// The graph in the graphviz DOT language, with each group of mutually recursive functions
+    /// in its own cluster.
+    pub fn as_dot(&self) -> String {
+        let mut dot = "digraph calls {".to_string();
+        for (i, scc) in self.sccs().iter().enumerate() {
+            if let [f] = &scc[..]
+                && !self.is_recursive(f)
+            {
+                dot.push_str(&format!("\n\"{f}\""));
+                continue;
+            }
+            dot.push_str(&format!("\nsubgraph cluster_{i} {{"));
+            for f in scc {
+                dot.push_str(&format!("\n\"{f}\""));
+            }
+            dot.push_str("\n}");
+        }
+        for f in self.calls.keys() {
+            for g in self.callees(f) {
+                dot.push_str(&format!("\n\"{f}\" -> \"{g}\""));
+            }
+        }
+        dot.push_str("\n}");
+        dot
+    }
+}
+
+/// Removes every function which can't be reached through calls from `main`. Programs without a
+/// `main` are left alone.
+pub fn eliminate_unreachable(prog: &mut Program) {
+    if !prog.functions.iter().any(|f| f.name == "main") {
+        return;
+    }
+    let reachable = CallGraph::from_program(prog).reachable("main");
+    prog.functions.retain(|f| reachable.contains(&f.name));
+}
@@ -0,0 +1,291 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::parser::ast::{EffectOp, Instruction, ValueOp};
+
+use super::{
+    analysis::{Cfg, Position, dest, uses},
+    data_flow::{ReachingDefinitions, ShimmedCfg},
+};
+
+/// The variables `insn` reads. A `get` reads its shadow variable, which has the name of its
+/// destination.
+fn reads(insn: &Instruction) -> impl Iterator<Item = &String> {
+    let shadow = match insn {
+        Instruction::Value {
+            op: ValueOp::Get,
+            dest,
+            ..
+        } => Some(dest),
+        _ => None,
+    };
+    uses(insn).iter().chain(shadow)
+}
+
+/// The shadow variable `insn` writes if it is a `set`.
+fn shadow(insn: &Instruction) -> Option<&String> {
+    match insn {
+        Instruction::Effect {
+            op: EffectOp::Set,
+            args,
+            ..
+        } => Some(&args[0]),
+        _ => None,
+    }
+}
+
+/// Def-use and use-def chains over the instructions of a [`Cfg`].
+///
+/// Instructions are identified by their [`Position`]: the start of their block and their index in
+/// it. A `get` reads its shadow variable from every `set` of it. Function arguments have no
+/// defining instruction, so reading one reads from no definitions.
+#[derive(Debug, Clone, Default)]
+pub struct Chains {
+    /// For every instruction, the definitions each variable it reads may come from.
+    reads: HashMap<Position, BTreeMap<String, BTreeSet<Position>>>,
+    /// For every definition, the instructions which may read it.
+    users: HashMap<Position, BTreeSet<Position>>,
+    /// Every definition of each variable.
+    names: HashMap<String, BTreeSet<Position>>,
+    /// Every `set` of each shadow variable.
+    shadows: HashMap<String, BTreeSet<Position>>,
+}
+
+impl Chains {
+    /// Every instruction of `cfg` with its position, in order.
+    pub(super) fn positions(cfg: &Cfg) -> impl Iterator<Item = (Position, &Instruction)> {
+        cfg.blocks.iter().flat_map(|block| {
+            block
+                .instrs
+                .iter()
+                .enumerate()
+                .map(|(i, insn)| ((block.start, i), insn))
+        })
+    }
+
+    fn with_defs(cfg: &Cfg) -> Self {
+        let mut chains = Self::default();
+        for (pos, insn) in Self::positions(cfg) {
+            chains.add_def(pos, insn);
+        }
+        chains
+    }
+
+    /// Links the chains for a function in SSA form, where every variable has a single definition.
+    ///
+    /// On code which isn't in SSA form every definition of a variable is taken to reach every read
+    /// of it, which is correct but imprecise.
+    pub fn from_ssa(cfg: &Cfg) -> Self {
+        let mut chains = Self::with_defs(cfg);
+        for (pos, insn) in Self::positions(cfg) {
+            for var in reads(insn) {
+                let defs = chains.all_defs(insn, var);
+                chains.link(pos, var, defs);
+            }
+        }
+        chains
+    }
+
+    /// Links the chains using reaching definitions, which works whether or not the function is in
+    /// SSA form.
+    pub fn from_reaching_definitions(cfg: &Cfg) -> Self {
+        let mut solved: ShimmedCfg<ReachingDefinitions> = ShimmedCfg::from_cfg(cfg);
+        solved.solve_bit_vector();
+        let mut chains = Self::with_defs(cfg);
+        for block in &cfg.blocks {
+            let mut reaching: HashMap<&String, BTreeSet<Position>> = HashMap::new();
+            for (def, var) in solved.inset(block.start) {
+                reaching.entry(var).or_default().insert(*def);
+            }
+            for (i, insn) in block.instrs.iter().enumerate() {
+                for var in reads(insn) {
+                    let defs = if shadow_read(insn, var) {
+                        chains.all_defs(insn, var)
+                    } else {
+                        reaching.get(var).cloned().unwrap_or_default()
+                    };
+                    chains.link((block.start, i), var, defs);
+                }
+                if let Some(dest) = dest(insn) {
+                    reaching.insert(dest, BTreeSet::from([(block.start, i)]));
+                }
+            }
+        }
+        chains
+    }
+
+    /// The definitions the instruction at `pos` may read `var` from.
+    pub fn defs(&self, pos: Position, var: &str) -> impl Iterator<Item = Position> + '_ {
+        self.reads
+            .get(&pos)
+            .and_then(|reads| reads.get(var))
+            .into_iter()
+            .flatten()
+            .copied()
+    }
+
+    /// Every variable the instruction at `pos` reads, with the definitions it may come from.
+    pub fn reads(&self, pos: Position) -> impl Iterator<Item = (&String, &BTreeSet<Position>)> {
+        self.reads.get(&pos).into_iter().flatten()
+    }
+
+    /// The instructions which may read the definition at `pos`.
+    pub fn users(&self, pos: Position) -> impl Iterator<Item = Position> + '_ {
+        self.users.get(&pos).into_iter().flatten().copied()
+    }
+
+    /// Every definition of `var` read by `insn`, whether it is a variable or a shadow variable.
+    fn all_defs(&self, insn: &Instruction, var: &str) -> BTreeSet<Position> {
+        let defs = if shadow_read(insn, var) {
+            &self.shadows
+        } else {
+            &self.names
+        };
+        defs.get(var).cloned().unwrap_or_default()
+    }
+
+    fn add_def(&mut self, pos: Position, insn: &Instruction) {
+        if let Some(dest) = dest(insn) {
+            self.names.entry(dest.clone()).or_default().insert(pos);
+        }
+        if let Some(shadow) = shadow(insn) {
+            self.shadows.entry(shadow.clone()).or_default().insert(pos);
+        }
+    }
+
+    fn remove_def(&mut self, pos: Position, insn: &Instruction) {
+        if let Some(dest) = dest(insn) {
+            self.names.get_mut(dest).unwrap().remove(&pos);
+        }
+        if let Some(shadow) = shadow(insn) {
+            self.shadows.get_mut(shadow).unwrap().remove(&pos);
+        }
+    }
+
+    fn link(&mut self, pos: Position, var: &str, defs: BTreeSet<Position>) {
+        for &def in &defs {
+            self.users.entry(def).or_default().insert(pos);
+        }
+        self.reads
+            .entry(pos)
+            .or_default()
+            .entry(var.to_string())
+            .or_default()
+            .extend(defs);
+    }
+
+    /// Forgets what the instruction at `pos` reads, returning it.
+    fn unlink(&mut self, pos: Position) -> BTreeMap<String, BTreeSet<Position>> {
+        let reads = self.reads.remove(&pos).unwrap_or_default();
+        for def in reads.values().flatten() {
+            if let Some(users) = self.users.get_mut(def) {
+                users.remove(&pos);
+            }
+        }
+        reads
+    }
+
+    /// Moves everything known about the instruction `insn` from `from` to `to`, which is free.
+    fn renumber(&mut self, insn: &Instruction, from: Position, to: Position) {
+        self.remove_def(from, insn);
+        self.add_def(to, insn);
+        if let Some(reads) = self.reads.remove(&from) {
+            for def in reads.values().flatten() {
+                let users = self.users.get_mut(def).unwrap();
+                users.remove(&from);
+                users.insert(to);
+            }
+            self.reads.insert(to, reads);
+        }
+        if let Some(users) = self.users.remove(&from) {
+            for user in &users {
+                for defs in self.reads.get_mut(user).unwrap().values_mut() {
+                    if defs.remove(&from) {
+                        defs.insert(to);
+                    }
+                }
+            }
+            self.users.insert(to, users);
+        }
+    }
+
+    /// Deletes the instruction at `pos` from `cfg` and updates the chains to match.
+    ///
+    /// Instructions which read a definition it made no longer read from it, and the instructions
+    /// after it in its block move up by one.
+    pub fn delete(&mut self, cfg: &mut Cfg, pos: Position) {
+        let (start, index) = pos;
+        let insn = cfg.block_mut(start).instrs.remove(index);
+        self.unlink(pos);
+        self.remove_def(pos, &insn);
+        for user in self.users.remove(&pos).unwrap_or_default() {
+            for defs in self.reads.get_mut(&user).unwrap().values_mut() {
+                defs.remove(&pos);
+            }
+        }
+        for (i, insn) in cfg.block(start).instrs.iter().enumerate().skip(index) {
+            self.renumber(insn, (start, i + 1), (start, i));
+        }
+    }
+
+    /// Replaces the instruction at `pos` in `cfg` with `insn` and updates the chains to match.
+    ///
+    /// `insn` has to define the same variable or shadow variable as the instruction it replaces, if
+    /// any, so that whatever read the old definition reads the new one. A variable the old
+    /// instruction also read comes from the same definitions as before, and any other from every
+    /// definition of it, which is exact in SSA form.
+    pub fn replace(&mut self, cfg: &mut Cfg, pos: Position, insn: Instruction) {
+        let (start, index) = pos;
+        let old = std::mem::replace(&mut cfg.block_mut(start).instrs[index], insn);
+        let insn = &cfg.block(start).instrs[index];
+        debug_assert!(
+            dest(&old) == dest(insn) && shadow(&old) == shadow(insn),
+            "replacement defines something else"
+        );
+        let old_reads = self.unlink(pos);
+        for var in reads(insn) {
+            let defs = match old_reads.get(var) {
+                Some(defs) => defs.clone(),
+                None => self.all_defs(insn, var),
+            };
+            self.link(pos, var, defs);
+        }
+    }
+
+    /// Prints what every instruction of `cfg` reads and who reads what it defines.
+    pub fn print(&self, cfg: &Cfg) {
+        let show = |positions: &mut dyn Iterator<Item = Position>| {
+            let shown: Vec<_> = positions.map(|(b, i)| format!("{b}.{i}")).collect();
+            if shown.is_empty() {
+                "nothing".to_string()
+            } else {
+                shown.join(" ")
+            }
+        };
+        for ((b, i), insn) in Self::positions(cfg) {
+            for (var, defs) in self.reads((b, i)) {
+                println!(
+                    "{b}.{i} reads {var} from {}",
+                    show(&mut defs.iter().copied())
+                );
+            }
+            if let Some(var) = dest(insn).or(shadow(insn)) {
+                println!(
+                    "{b}.{i} defines {var} for {}",
+                    show(&mut self.users((b, i)))
+                );
+            }
+        }
+    }
+}
+
+/// Whether `insn` reading `var` reads a shadow variable, which only a `get` does.
+fn shadow_read(insn: &Instruction, var: &str) -> bool {
+    matches!(
+        insn,
+        Instruction::Value {
+            op: ValueOp::Get,
+            dest,
+            ..
+        } if dest == var
+    )
+}
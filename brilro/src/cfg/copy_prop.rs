@@ -0,0 +1,65 @@
+use crate::parser::ast::{EffectOp, Instruction, ValueOp};
+
+use super::{
+    analysis::Cfg,
+    data_flow::{AvailableCopies, ReachingDefinitions, ShimmedCfg},
+};
+
+impl Cfg {
+    /// Global copy propagation.
+    ///
+    /// A use of `v` is replaced with `s` when the only definition of `v` reaching it is the copy
+    /// `v = id s`, and `s` hasn't been reassigned on any path since. The copies are left for dce.
+    pub fn copy_propagation(&mut self) {
+        while self.propagate_copies() {}
+    }
+
+    /// Returns true on replacing something.
+    fn propagate_copies(&mut self) -> bool {
+        let mut reaching: ShimmedCfg<ReachingDefinitions> = ShimmedCfg::from_cfg(self);
+        reaching.solve_bit_vector();
+        let mut copies: ShimmedCfg<AvailableCopies> = ShimmedCfg::from_cfg(self);
+        copies.solve_bit_vector();
+
+        let mut replaced = false;
+        let old_blocks = self.blocks.clone();
+        for block in self.blocks.iter_mut() {
+            let mut defs = reaching.inset(block.start).clone();
+            let mut available = copies.inset(block.start).clone().unwrap_or_default();
+            for (idx, insn) in block.instrs.iter_mut().enumerate() {
+                let is_set = matches!(
+                    insn,
+                    Instruction::Effect {
+                        op: EffectOp::Set,
+                        ..
+                    }
+                );
+                if let Instruction::Value { args, .. } | Instruction::Effect { args, .. } = insn {
+                    // The first argument of a `set` names a shadow variable rather than a value.
+                    for arg in args.iter_mut().skip(usize::from(is_set)) {
+                        let mut reaching_arg = defs.iter().filter(|(_, v)| v == arg);
+                        let (Some(((def_block, def_idx), _)), None) =
+                            (reaching_arg.next(), reaching_arg.next())
+                        else {
+                            continue;
+                        };
+                        let def_block = old_blocks.iter().find(|b| b.start == *def_block).unwrap();
+                        if let Instruction::Value {
+                            op: ValueOp::Id,
+                            args: src,
+                            ..
+                        } = &def_block.instrs[*def_idx]
+                            && available.contains(&(arg.clone(), src[0].clone()))
+                        {
+                            *arg = src[0].clone();
+                            replaced = true;
+                        }
+                    }
+                }
+                ReachingDefinitions::step(&mut defs, (block.start, idx), insn);
+                AvailableCopies::step(&mut available, insn);
+            }
+        }
+        replaced
+    }
+}
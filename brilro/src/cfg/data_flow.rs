@@ -1,8 +1,14 @@
-use std::collections::BTreeSet;
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::Hash,
+};
 
 use crate::parser::ast::*;
 
-use super::analysis::{BasicBlock, Cfg};
+use super::{
+    analysis::{BasicBlock, Cfg, Position, dest},
+    bit_vector::{BitSet, Interner},
+};
 
 #[derive(Debug)]
 pub struct Info<S> {
@@ -14,33 +20,196 @@ pub struct Info<S> {
 pub trait Flow {
     type Set: Clone + Eq + std::fmt::Debug;
 
-    fn transfer(block: &mut Info<Self::Set>);
+    /// Updates `set` to what holds after the instruction `insn` at `pos`.
+    fn transfer_instruction(set: &mut Self::Set, pos: Position, insn: &Instruction);
+
+    fn transfer(block: &mut Info<Self::Set>) {
+        let mut outset = block.inset.clone();
+        for (i, insn) in block.block.instrs.iter().enumerate() {
+            Self::transfer_instruction(&mut outset, (block.block.start, i), insn);
+        }
+        block.outset = outset;
+    }
     fn merge(a: &Self::Set, b: &Self::Set) -> Self::Set;
     fn inital() -> Self::Set;
 
+    /// The set flowing into the entry block, merged with anything from its predecessors.
+    fn entry() -> Self::Set {
+        Self::inital()
+    }
+
     fn string_of_set(s: &Self::Set) -> String;
 }
 
+/// How a block combines what flows out of its predecessors.
+pub enum Meet {
+    /// A fact holds if it holds along any incoming path.
+    Union,
+    /// A fact holds if it holds along every incoming path.
+    Intersection,
+}
+
+/// A [`Flow`] over a set of facts known up front, where each instruction kills some facts and
+/// then generates others, so it can be solved on dense bit vectors.
+///
+/// The entry block starts with no facts. Other blocks start empty for [`Meet::Union`] and with
+/// every fact for [`Meet::Intersection`], and for the latter the blocks the entry can't reach are
+/// left at [`Flow::inital`], since no path makes anything hold there.
+pub trait GenKill: Flow {
+    /// The facts of a function with their numbers, and anything else needed to find what an
+    /// instruction generates and kills.
+    type Universe;
+
+    const MEET: Meet;
+
+    fn universe<'a>(blocks: impl Iterator<Item = &'a BasicBlock>) -> Self::Universe;
+
+    /// The number of facts in `universe`.
+    fn size(universe: &Self::Universe) -> usize;
+
+    /// Adds the facts the instruction `insn` at `pos` generates to `generated` and the ones it
+    /// kills to `killed`.
+    fn gen_kill(
+        universe: &Self::Universe,
+        pos: Position,
+        insn: &Instruction,
+        generated: &mut BitSet,
+        killed: &mut BitSet,
+    );
+
+    /// Turns a bit vector back into the set the [`Flow`] works with.
+    fn decode(universe: &Self::Universe, set: &BitSet) -> Self::Set;
+}
+
+/// The definitions which may reach a point, each with the position of the defining instruction.
 pub struct ReachingDefinitions {}
 
-impl Flow for ReachingDefinitions {
-    type Set = BTreeSet<(usize, String)>;
+impl ReachingDefinitions {
+    /// Updates `set` to the definitions reaching past `insn` at `pos`.
+    pub fn step(set: &mut BTreeSet<(Position, String)>, pos: Position, insn: &Instruction) {
+        match insn {
+            Instruction::Constant { dest, .. } | Instruction::Value { dest, .. } => {
+                set.retain(|(_, s)| s != dest);
+                set.insert((pos, dest.clone()));
+            }
+            Instruction::Effect { .. } | Instruction::Label { .. } => {}
+        }
+    }
+}
 
-    fn transfer(block: &mut Info<Self::Set>) {
-        let mut outset = block.inset.clone();
-        for insn in &block.block.instrs {
-            match insn {
-                Instruction::Constant { dest, .. } | Instruction::Value { dest, .. } => {
-                    if let Some(p) = outset.iter().find(|(_, s)| s == dest) {
-                        let p = p.clone();
-                        outset.remove(&p);
-                    }
-                    outset.insert((block.block.start, dest.clone()));
+/// The facts of a function numbered for a [`BitSet`], with the ones assigning each variable kills.
+pub struct Facts<T> {
+    facts: Interner<T>,
+    vars: Interner<String>,
+    killed_by: Vec<BitSet>,
+}
+
+impl<T: Clone + Eq + Hash> Facts<T> {
+    /// Numbers every fact, each given with the variables whose assignment kills it.
+    fn new(facts: impl IntoIterator<Item = (T, Vec<String>)>) -> Self {
+        let mut numbered = Interner::default();
+        let mut vars = Interner::default();
+        let mut killed_by: Vec<Vec<usize>> = vec![];
+        for (fact, kill_vars) in facts {
+            let fact = numbered.intern(&fact);
+            for var in &kill_vars {
+                let var = vars.intern(var);
+                if var == killed_by.len() {
+                    killed_by.push(vec![]);
                 }
-                Instruction::Effect { .. } | Instruction::Label { .. } => {}
+                killed_by[var].push(fact);
             }
         }
-        block.outset = outset;
+        let killed_by = killed_by
+            .into_iter()
+            .map(|var_facts| {
+                let mut set = BitSet::new(numbered.len());
+                for fact in var_facts {
+                    set.insert(fact);
+                }
+                set
+            })
+            .collect();
+        Self {
+            facts: numbered,
+            vars,
+            killed_by,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.facts.len()
+    }
+
+    /// Adds the facts assigning `var` kills to `killed`.
+    fn kill(&self, var: &String, killed: &mut BitSet) {
+        if let Some(var) = self.vars.number(var) {
+            killed.union_with(&self.killed_by[var]);
+        }
+    }
+
+    /// Adds `fact`, which has to be one of the facts, to `generated`.
+    fn generate(&self, fact: &T, generated: &mut BitSet) {
+        generated.insert(self.facts.number(fact).unwrap());
+    }
+
+    fn decode<'a>(&'a self, set: &'a BitSet) -> impl Iterator<Item = T> + 'a {
+        set.iter().map(|fact| self.facts.value(fact).clone())
+    }
+}
+
+/// Every instruction of `blocks` with its position.
+fn instructions<'a>(
+    blocks: impl Iterator<Item = &'a BasicBlock>,
+) -> impl Iterator<Item = (Position, &'a Instruction)> {
+    blocks.flat_map(|block| {
+        block
+            .instrs
+            .iter()
+            .enumerate()
+            .map(|(i, insn)| ((block.start, i), insn))
+    })
+}
+
+impl GenKill for ReachingDefinitions {
+    type Universe = Facts<(Position, String)>;
+
+    const MEET: Meet = Meet::Union;
+
+    fn universe<'a>(blocks: impl Iterator<Item = &'a BasicBlock>) -> Self::Universe {
+        Facts::new(instructions(blocks).filter_map(|(pos, insn)| {
+            let dest = dest(insn)?;
+            Some(((pos, dest.clone()), vec![dest.clone()]))
+        }))
+    }
+
+    fn size(universe: &Self::Universe) -> usize {
+        universe.len()
+    }
+
+    fn gen_kill(
+        universe: &Self::Universe,
+        pos: Position,
+        insn: &Instruction,
+        generated: &mut BitSet,
+        killed: &mut BitSet,
+    ) {
+        if let Some(dest) = dest(insn) {
+            universe.kill(dest, killed);
+            universe.generate(&(pos, dest.clone()), generated);
+        }
+    }
+
+    fn decode(universe: &Self::Universe, set: &BitSet) -> Self::Set {
+        universe.decode(set).collect()
+    }
+}
+
+impl Flow for ReachingDefinitions {
+    type Set = BTreeSet<(Position, String)>;
+
+    fn transfer_instruction(set: &mut Self::Set, pos: Position, insn: &Instruction) {
+        Self::step(set, pos, insn);
     }
 
     fn merge(a: &Self::Set, b: &Self::Set) -> Self::Set {
@@ -52,15 +221,408 @@ impl Flow for ReachingDefinitions {
     }
 
     fn string_of_set(s: &Self::Set) -> String {
-        let mut out = "".to_string();
-        for (k, v) in s {
-            if !out.is_empty() {
-                out = format!("{out}, ({k}: {v})");
-            } else {
-                out = format!("({k}: {v})");
+        s.iter()
+            .map(|((b, i), v)| format!("({b}.{i}: {v})"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// An expression computed by a pure value operation, keyed by the names of its operands.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Expression {
+    pub op: ValueOp,
+    pub args: Vec<String>,
+}
+
+impl Expression {
+    /// Returns the expression computed by `insn`, if it is one worth reusing.
+    ///
+    /// Operands of commutative operations are sorted so `add a b` and `add b a` are the same.
+    pub fn from_instruction(insn: &Instruction) -> Option<Self> {
+        match insn {
+            Instruction::Value { op, args, .. } => {
+                let mut args = args.clone();
+                match op {
+                    ValueOp::Call | ValueOp::Id | ValueOp::Get | ValueOp::Undef | ValueOp::Phi => {
+                        return None;
+                    }
+                    ValueOp::Add | ValueOp::Mul | ValueOp::Eq | ValueOp::And | ValueOp::Or => {
+                        args.sort()
+                    }
+                    _ => {}
+                }
+                Some(Self {
+                    op: op.clone(),
+                    args,
+                })
             }
+            Instruction::Constant { .. }
+            | Instruction::Effect { .. }
+            | Instruction::Label { .. } => None,
+        }
+    }
+}
+
+/// Which expressions are held in which variables on every path to a point.
+///
+/// A set of `None` stands for every expression, which is what unvisited blocks start with and what
+/// blocks the entry can't reach keep.
+pub struct AvailableExpressions {}
+
+impl AvailableExpressions {
+    /// The expression `insn` leaves available in its destination, if any.
+    fn generated(insn: &Instruction) -> Option<(Expression, String)> {
+        let dest = dest(insn)?;
+        Expression::from_instruction(insn)
+            .filter(|e| !e.args.contains(dest))
+            .map(|e| (e, dest.clone()))
+    }
+
+    /// Updates `set` to what is available after `insn`.
+    pub fn step(set: &mut BTreeSet<(Expression, String)>, insn: &Instruction) {
+        if let Some(dest) = dest(insn) {
+            set.retain(|(e, v)| v != dest && !e.args.contains(dest));
+            set.extend(Self::generated(insn));
+        }
+    }
+}
+
+impl GenKill for AvailableExpressions {
+    type Universe = Facts<(Expression, String)>;
+
+    const MEET: Meet = Meet::Intersection;
+
+    fn universe<'a>(blocks: impl Iterator<Item = &'a BasicBlock>) -> Self::Universe {
+        Facts::new(instructions(blocks).filter_map(|(_, insn)| {
+            let (e, v) = Self::generated(insn)?;
+            let vars = e.args.iter().cloned().chain([v.clone()]).collect();
+            Some(((e, v), vars))
+        }))
+    }
+
+    fn size(universe: &Self::Universe) -> usize {
+        universe.len()
+    }
+
+    fn gen_kill(
+        universe: &Self::Universe,
+        _: Position,
+        insn: &Instruction,
+        generated: &mut BitSet,
+        killed: &mut BitSet,
+    ) {
+        if let Some(dest) = dest(insn) {
+            universe.kill(dest, killed);
+        }
+        if let Some(fact) = Self::generated(insn) {
+            universe.generate(&fact, generated);
+        }
+    }
+
+    fn decode(universe: &Self::Universe, set: &BitSet) -> Self::Set {
+        Some(universe.decode(set).collect())
+    }
+}
+
+impl Flow for AvailableExpressions {
+    type Set = Option<BTreeSet<(Expression, String)>>;
+
+    fn transfer_instruction(set: &mut Self::Set, _: Position, insn: &Instruction) {
+        if let Some(set) = set {
+            Self::step(set, insn);
+        }
+    }
+
+    fn merge(a: &Self::Set, b: &Self::Set) -> Self::Set {
+        match (a, b) {
+            (None, s) | (s, None) => s.clone(),
+            (Some(a), Some(b)) => Some(a.intersection(b).cloned().collect()),
+        }
+    }
+
+    fn inital() -> Self::Set {
+        None
+    }
+
+    fn entry() -> Self::Set {
+        Some(BTreeSet::new())
+    }
+
+    fn string_of_set(s: &Self::Set) -> String {
+        match s {
+            None => "everything".to_string(),
+            Some(s) => s
+                .iter()
+                .map(|(e, v)| {
+                    let op = format!("{:?}", e.op).to_lowercase();
+                    format!("({v}: {op} {})", e.args.join(" "))
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+}
+
+/// Copies `dest = id src` where neither side has been reassigned since, on every path to a point.
+///
+/// A set of `None` stands for every copy, which is what unvisited blocks start with and what
+/// blocks the entry can't reach keep.
+pub struct AvailableCopies {}
+
+impl AvailableCopies {
+    /// The copy `insn` makes, if it is one.
+    fn generated(insn: &Instruction) -> Option<(String, String)> {
+        match insn {
+            Instruction::Value {
+                op: ValueOp::Id,
+                dest,
+                args,
+                ..
+            } if args[0] != *dest => Some((dest.clone(), args[0].clone())),
+            _ => None,
+        }
+    }
+
+    /// Updates `set` to the copies available after `insn`.
+    pub fn step(set: &mut BTreeSet<(String, String)>, insn: &Instruction) {
+        if let Some(dest) = dest(insn) {
+            set.retain(|(d, s)| d != dest && s != dest);
+            set.extend(Self::generated(insn));
+        }
+    }
+}
+
+impl GenKill for AvailableCopies {
+    type Universe = Facts<(String, String)>;
+
+    const MEET: Meet = Meet::Intersection;
+
+    fn universe<'a>(blocks: impl Iterator<Item = &'a BasicBlock>) -> Self::Universe {
+        Facts::new(instructions(blocks).filter_map(|(_, insn)| {
+            let (d, s) = Self::generated(insn)?;
+            let vars = vec![d.clone(), s.clone()];
+            Some(((d, s), vars))
+        }))
+    }
+
+    fn size(universe: &Self::Universe) -> usize {
+        universe.len()
+    }
+
+    fn gen_kill(
+        universe: &Self::Universe,
+        _: Position,
+        insn: &Instruction,
+        generated: &mut BitSet,
+        killed: &mut BitSet,
+    ) {
+        if let Some(dest) = dest(insn) {
+            universe.kill(dest, killed);
+        }
+        if let Some(fact) = Self::generated(insn) {
+            universe.generate(&fact, generated);
+        }
+    }
+
+    fn decode(universe: &Self::Universe, set: &BitSet) -> Self::Set {
+        Some(universe.decode(set).collect())
+    }
+}
+
+impl Flow for AvailableCopies {
+    type Set = Option<BTreeSet<(String, String)>>;
+
+    fn transfer_instruction(set: &mut Self::Set, _: Position, insn: &Instruction) {
+        if let Some(set) = set {
+            Self::step(set, insn);
+        }
+    }
+
+    fn merge(a: &Self::Set, b: &Self::Set) -> Self::Set {
+        match (a, b) {
+            (None, s) | (s, None) => s.clone(),
+            (Some(a), Some(b)) => Some(a.intersection(b).cloned().collect()),
+        }
+    }
+
+    fn inital() -> Self::Set {
+        None
+    }
+
+    fn entry() -> Self::Set {
+        Some(BTreeSet::new())
+    }
+
+    fn string_of_set(s: &Self::Set) -> String {
+        match s {
+            None => "everything".to_string(),
+            Some(s) => s
+                .iter()
+                .map(|(d, s)| format!("({d}: id {s})"))
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+}
+
+/// The variables assigned on every path to a point. Function parameters aren't included, since
+/// [`Flow::entry`] can't see them.
+pub struct DefinedVariables {}
+
+impl GenKill for DefinedVariables {
+    type Universe = Facts<String>;
+
+    const MEET: Meet = Meet::Intersection;
+
+    fn universe<'a>(blocks: impl Iterator<Item = &'a BasicBlock>) -> Self::Universe {
+        Facts::new(instructions(blocks).filter_map(|(_, insn)| Some((dest(insn)?.clone(), vec![]))))
+    }
+
+    fn size(universe: &Self::Universe) -> usize {
+        universe.len()
+    }
+
+    fn gen_kill(
+        universe: &Self::Universe,
+        _: Position,
+        insn: &Instruction,
+        generated: &mut BitSet,
+        _: &mut BitSet,
+    ) {
+        if let Some(dest) = dest(insn) {
+            universe.generate(dest, generated);
+        }
+    }
+
+    fn decode(universe: &Self::Universe, set: &BitSet) -> Self::Set {
+        Some(universe.decode(set).collect())
+    }
+}
+
+impl Flow for DefinedVariables {
+    type Set = Option<BTreeSet<String>>;
+
+    fn transfer_instruction(set: &mut Self::Set, _: Position, insn: &Instruction) {
+        if let Some(set) = set
+            && let Some(dest) = dest(insn)
+        {
+            set.insert(dest.clone());
+        }
+    }
+
+    fn merge(a: &Self::Set, b: &Self::Set) -> Self::Set {
+        match (a, b) {
+            (None, s) | (s, None) => s.clone(),
+            (Some(a), Some(b)) => Some(a.intersection(b).cloned().collect()),
+        }
+    }
+
+    fn inital() -> Self::Set {
+        None
+    }
+
+    fn entry() -> Self::Set {
+        Some(BTreeSet::new())
+    }
+
+    fn string_of_set(s: &Self::Set) -> String {
+        match s {
+            None => "everything".to_string(),
+            Some(s) => s.iter().cloned().collect::<Vec<_>>().join(", "),
+        }
+    }
+}
+
+/// The edges between blocks by index, and the order to visit them in, worked out once before
+/// solving rather than searched for on every step.
+struct Order {
+    preds: Vec<Vec<usize>>,
+    succs: Vec<Vec<usize>>,
+    /// Blocks in reverse postorder from the entry, then the unreachable ones.
+    rpo: Vec<usize>,
+    /// How many blocks the entry reaches, which come first in `rpo`.
+    reachable: usize,
+    /// The place of each block in `rpo`.
+    rank: Vec<usize>,
+}
+
+impl Order {
+    fn new<S>(blocks: &[Info<S>]) -> Self {
+        let index: HashMap<usize, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(i, info)| (info.block.start, i))
+            .collect();
+        let mut preds = vec![vec![]; blocks.len()];
+        let mut succs = vec![vec![]; blocks.len()];
+        for (b, info) in blocks.iter().enumerate() {
+            for s in &info.block.flows_to {
+                let s = index[s];
+                if !succs[b].contains(&s) {
+                    succs[b].push(s);
+                    preds[s].push(b);
+                }
+            }
+        }
+
+        let mut postorder = vec![];
+        let mut seen = vec![false; blocks.len()];
+        if let Some(entry) = index.get(&0) {
+            seen[*entry] = true;
+            // Each entry is a block and how many of its successors have been visited.
+            let mut stack = vec![(*entry, 0)];
+            while let Some((b, next)) = stack.pop() {
+                if let Some(&s) = succs[b].get(next) {
+                    stack.push((b, next + 1));
+                    if !seen[s] {
+                        seen[s] = true;
+                        stack.push((s, 0));
+                    }
+                } else {
+                    postorder.push(b);
+                }
+            }
+        }
+        let mut rpo: Vec<usize> = postorder.into_iter().rev().collect();
+        let reachable = rpo.len();
+        rpo.extend((0..blocks.len()).filter(|b| !seen[*b]));
+        let mut rank = vec![0; blocks.len()];
+        for (i, &b) in rpo.iter().enumerate() {
+            rank[b] = i;
+        }
+        Self {
+            preds,
+            succs,
+            rpo,
+            reachable,
+            rank,
+        }
+    }
+
+    /// Visits blocks until `visit` stops reporting changes, starting with every block and
+    /// revisiting the successors of a block whenever it changes.
+    ///
+    /// Blocks are visited in passes over the reverse postorder, so a successor further along is
+    /// seen in the same pass and one back along a loop waits for the next.
+    fn run(&self, mut visit: impl FnMut(usize) -> bool) {
+        let mut pass: BTreeSet<usize> = (0..self.rpo.len()).collect();
+        let mut next_pass = BTreeSet::new();
+        while !pass.is_empty() {
+            while let Some(rank) = pass.pop_first() {
+                let b = self.rpo[rank];
+                if visit(b) {
+                    for s in &self.succs[b] {
+                        if self.rank[*s] > rank {
+                            pass.insert(self.rank[*s]);
+                        } else {
+                            next_pass.insert(self.rank[*s]);
+                        }
+                    }
+                }
+            }
+            std::mem::swap(&mut pass, &mut next_pass);
         }
-        out
     }
 }
 
@@ -84,23 +646,77 @@ impl<T: Flow> ShimmedCfg<T> {
     }
 
     pub fn solve(&mut self) {
-        let mut worklist: Vec<usize> = (0..self.blocks.len()).collect();
-        while let Some(b) = worklist.pop() {
-            let parents = self
-                .blocks
+        let order = Order::new(&self.blocks);
+        order.run(|b| {
+            let init = if self.blocks[b].block.start == 0 {
+                T::entry()
+            } else {
+                T::inital()
+            };
+            let merged = order.preds[b]
                 .iter()
-                .filter(|i| i.block.flows_to.contains(&self.blocks[b].block.start));
-            let merged = parents.fold(T::inital(), |acc, p| T::merge(&p.outset, &acc));
+                .fold(init, |acc, &p| T::merge(&self.blocks[p].outset, &acc));
             self.blocks[b].inset = merged;
             let last_out = self.blocks[b].outset.clone();
             T::transfer(&mut self.blocks[b]);
-            if last_out != self.blocks[b].outset {
-                for &s in &self.blocks[b].block.flows_to {
-                    let to_push = self.blocks.iter().position(|p| p.block.start == s).unwrap();
-                    worklist.push(to_push);
-                }
-            }
+            last_out != self.blocks[b].outset
+        });
+    }
+
+    fn info(&self, start: usize) -> &Info<T::Set> {
+        self.blocks.iter().find(|i| i.block.start == start).unwrap()
+    }
+
+    /// Returns the solved inset of the block given its start.
+    ///
+    /// Panics if start isn't found.
+    pub fn inset(&self, start: usize) -> &T::Set {
+        &self.info(start).inset
+    }
+
+    /// Returns the solved outset of the block given its start.
+    ///
+    /// Panics if start isn't found.
+    pub fn outset(&self, start: usize) -> &T::Set {
+        &self.info(start).outset
+    }
+
+    /// Returns what holds before each instruction of the block given its start, followed by its
+    /// outset.
+    ///
+    /// Panics if start isn't found.
+    pub fn facts(&self, start: usize) -> Vec<T::Set> {
+        let info = self.info(start);
+        let mut set = info.inset.clone();
+        let mut facts = vec![set.clone()];
+        for (i, insn) in info.block.instrs.iter().enumerate() {
+            T::transfer_instruction(&mut set, (start, i), insn);
+            facts.push(set.clone());
         }
+        facts
+    }
+
+    /// Returns what holds just before the instruction at `pos`, or the outset if `pos` is just past
+    /// the end of its block.
+    ///
+    /// Panics if there is no such position.
+    pub fn before(&self, (start, index): Position) -> T::Set {
+        let info = self.info(start);
+        let mut set = info.inset.clone();
+        for (i, insn) in info.block.instrs[..index].iter().enumerate() {
+            T::transfer_instruction(&mut set, (start, i), insn);
+        }
+        set
+    }
+
+    /// Returns what holds just after the instruction at `pos`.
+    ///
+    /// Panics if there is no such instruction.
+    pub fn after(&self, (start, index): Position) -> T::Set {
+        let insn = &self.info(start).block.instrs[index];
+        let mut set = self.before((start, index));
+        T::transfer_instruction(&mut set, (start, index), insn);
+        set
     }
 
     pub fn print_outsets(&self) {
@@ -108,4 +724,119 @@ impl<T: Flow> ShimmedCfg<T> {
             println!("{}: {}", block.block.start, T::string_of_set(&block.outset));
         }
     }
+
+    /// Prints the function as Bril text with what holds between every two instructions in
+    /// comments, so an analysis can be followed line by line.
+    pub fn print_annotated(&self, cfg: &Cfg) {
+        let f = &cfg.original_function;
+        let args: Vec<_> = f
+            .args
+            .iter()
+            .map(|a| format!("{}: {}", a.name, a.ty))
+            .collect();
+        let args = if args.is_empty() {
+            "".to_string()
+        } else {
+            format!("({})", args.join(", "))
+        };
+        let ty = f.ty.map(|ty| format!(": {ty}")).unwrap_or_default();
+        println!("@{}{args}{ty} {{", f.name);
+        let comment = |fact: &T::Set| {
+            format!("  # {}", T::string_of_set(fact))
+                .trim_end()
+                .to_string()
+        };
+        for (n, block) in self.blocks.iter().enumerate() {
+            if n > 0 {
+                println!();
+            }
+            let facts = self.facts(block.block.start);
+            for (insn, fact) in block.block.instrs.iter().zip(&facts) {
+                println!("{}", comment(fact));
+                match insn {
+                    Instruction::Label { .. } => println!("{insn}"),
+                    _ => println!("  {insn}"),
+                }
+            }
+            println!("{}", comment(facts.last().unwrap()));
+        }
+        println!("}}");
+    }
+}
+
+impl<T: GenKill> ShimmedCfg<T> {
+    /// Solves the same equations as [`Self::solve`] with every set as a dense bit vector, which is
+    /// much faster on large functions, then turns the results back into sets.
+    pub fn solve_bit_vector(&mut self) {
+        let universe = T::universe(self.blocks.iter().map(|info| &info.block));
+        let size = T::size(&universe);
+        let mut generated = BitSet::new(size);
+        let mut killed = BitSet::new(size);
+        // What each block generates and kills as a whole.
+        let transfers: Vec<(BitSet, BitSet)> = self
+            .blocks
+            .iter()
+            .map(|info| {
+                let mut block_gen = BitSet::new(size);
+                let mut block_kill = BitSet::new(size);
+                for (i, insn) in info.block.instrs.iter().enumerate() {
+                    generated.clear();
+                    killed.clear();
+                    T::gen_kill(
+                        &universe,
+                        (info.block.start, i),
+                        insn,
+                        &mut generated,
+                        &mut killed,
+                    );
+                    block_gen.subtract(&killed);
+                    block_gen.union_with(&generated);
+                    block_kill.union_with(&killed);
+                }
+                (block_gen, block_kill)
+            })
+            .collect();
+
+        let init = match T::MEET {
+            Meet::Union => BitSet::new(size),
+            Meet::Intersection => BitSet::full(size),
+        };
+        let mut insets = vec![init.clone(); self.blocks.len()];
+        let mut outsets = vec![init.clone(); self.blocks.len()];
+        let order = Order::new(&self.blocks);
+        order.run(|b| {
+            let mut inset = if self.blocks[b].block.start == 0 {
+                BitSet::new(size)
+            } else {
+                init.clone()
+            };
+            for &p in &order.preds[b] {
+                match T::MEET {
+                    Meet::Union => inset.union_with(&outsets[p]),
+                    // An unreachable block holds everything, as its set does in `solve`, so it
+                    // can't take anything away from a block it jumps to.
+                    Meet::Intersection if order.rank[p] >= order.reachable => {}
+                    Meet::Intersection => inset.intersect_with(&outsets[p]),
+                }
+            }
+            let (block_gen, block_kill) = &transfers[b];
+            let mut outset = inset.clone();
+            outset.subtract(block_kill);
+            outset.union_with(block_gen);
+            insets[b] = inset;
+            let changed = outset != outsets[b];
+            outsets[b] = outset;
+            changed
+        });
+
+        for (b, info) in self.blocks.iter_mut().enumerate() {
+            if matches!(T::MEET, Meet::Intersection) && order.rank[b] >= order.reachable {
+                info.inset = T::inital();
+                info.outset = T::inital();
+            } else {
+                info.inset = T::decode(&universe, &insets[b]);
+                info.outset = T::decode(&universe, &outsets[b]);
+            }
+        }
+    }
 }
@@ -1,12 +1,16 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::parser::ast::{EffectOp, Instruction};
+use crate::parser::ast::{EffectOp, Instruction, ValueOp};
 
-use super::analysis::{BasicBlock, Cfg};
+use super::{
+    analysis::{BasicBlock, Cfg, Position},
+    chains::Chains,
+    purity::Purity,
+};
 
 impl BasicBlock {
     /// Returns true on eliminating something.
-    fn eliminate_dead_code(&mut self) -> bool {
+    fn eliminate_dead_code(&mut self, purity: &Purity) -> bool {
         let mut dead = HashSet::new();
         let mut maybe_dead: HashMap<&String, usize> = HashMap::new();
         for (i, insn) in self.instrs.iter().enumerate() {
@@ -26,7 +30,12 @@ impl BasicBlock {
                 if maybe_dead.contains_key(dest) {
                     dead.insert(maybe_dead[dest]);
                 }
-                maybe_dead.insert(dest, i);
+                // A call with side effects stays even if its result is overwritten.
+                if purity.is_impure_call(insn) {
+                    maybe_dead.remove(dest);
+                } else {
+                    maybe_dead.insert(dest, i);
+                }
             }
         }
 
@@ -48,14 +57,18 @@ impl BasicBlock {
         eliminated_something
     }
 
-    pub fn dce(&mut self) {
-        while self.eliminate_dead_code() {}
+    /// Calls are kept unless `purity` says the callee is pure.
+    pub fn dce(&mut self, purity: &Purity) {
+        while self.eliminate_dead_code(purity) {}
     }
 }
 
 impl Cfg {
     /// Basic block dce is nice, but a function global dce is also kind of needed.
-    pub fn dce(&mut self) {
+    ///
+    /// Calls are kept unless `purity` says the callee is pure, in which case they go if their
+    /// result is unused.
+    pub fn dce(&mut self, purity: &Purity) {
         loop {
             let assigned =
                 self.blocks
@@ -94,6 +107,13 @@ impl Cfg {
             let unused: HashSet<_> = assigned.difference(&used).collect();
             for block in self.blocks.iter_mut() {
                 block.instrs.retain(|i| match i {
+                    _ if purity.is_impure_call(i) => true,
+                    Instruction::Effect {
+                        op: EffectOp::Call, ..
+                    } => {
+                        removed_insn = true;
+                        false
+                    }
                     Instruction::Value { dest, .. } | Instruction::Constant { dest, .. } => {
                         if unused.contains(dest) {
                             removed_insn = true;
@@ -118,7 +138,7 @@ impl Cfg {
             while block_removed_insn {
                 block_removed_insn = false;
                 for block in self.blocks.iter_mut() {
-                    block_removed_insn |= block.eliminate_dead_code();
+                    block_removed_insn |= block.eliminate_dead_code(purity);
                     removed_insn |= block_removed_insn;
                 }
             }
@@ -128,3 +148,59 @@ impl Cfg {
         }
     }
 }
+
+/// Whether `insn` has to stay no matter if anything reads its result.
+fn is_root(insn: &Instruction, purity: &Purity) -> bool {
+    match insn {
+        Instruction::Effect {
+            op: EffectOp::Set, ..
+        } => false,
+        Instruction::Effect {
+            op: EffectOp::Call, ..
+        }
+        | Instruction::Value {
+            op: ValueOp::Call, ..
+        } => purity.is_impure_call(insn),
+        Instruction::Effect { .. } => true,
+        Instruction::Value { .. } | Instruction::Constant { .. } | Instruction::Label { .. } => {
+            false
+        }
+    }
+}
+
+/// Dead code elimination for a CFG in SSA form, in either style.
+///
+/// Instead of deleting unused definitions until nothing changes, this marks everything reachable
+/// from the instructions with side effects through def-use chains and deletes the rest. A live
+/// `get` keeps every `set` of its shadow variable, so `phi`s or `get`/`set` pairs which only feed
+/// each other around a loop go away as a whole. Branches are always kept.
+pub fn ssa_dce(cfg: &Cfg, purity: &Purity) -> Cfg {
+    let mut chains = Chains::from_ssa(cfg);
+    let mut worklist = vec![];
+    for block in &cfg.blocks {
+        for (i, insn) in block.instrs.iter().enumerate() {
+            if is_root(insn, purity) {
+                worklist.push((block.start, i));
+            }
+        }
+    }
+
+    let mut live = HashSet::new();
+    while let Some(pos) = worklist.pop() {
+        if live.insert(pos) {
+            worklist.extend(chains.reads(pos).flat_map(|(_, defs)| defs));
+        }
+    }
+
+    // Deleting from the back of each block leaves the positions still to delete where they were.
+    let mut dead: Vec<Position> = Chains::positions(cfg)
+        .filter(|(pos, insn)| !matches!(insn, Instruction::Label { .. }) && !live.contains(pos))
+        .map(|(pos, _)| pos)
+        .collect();
+    dead.reverse();
+    let mut cfg = cfg.clone();
+    for pos in dead {
+        chains.delete(&mut cfg, pos);
+    }
+    cfg
+}
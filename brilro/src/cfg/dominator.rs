@@ -95,6 +95,37 @@ impl DominatorTree {
         }
     }
 
+    /// Returns the immediate dominator of `block`.
+    ///
+    /// This is `None` for the entry block and for blocks unreachable from it.
+    pub fn immediate_dominator(&self, block: usize) -> Option<usize> {
+        let strict: Vec<usize> = self
+            .dom
+            .iter()
+            .filter(|(d, domed)| **d != block && domed.contains(&block))
+            .map(|(d, _)| *d)
+            .collect();
+        strict
+            .iter()
+            .copied()
+            .find(|&d| strict.iter().all(|o| self.dom[o].contains(&d)))
+    }
+
+    /// Returns the children of each block in the dominator tree, sorted by block start.
+    pub fn tree(&self) -> HashMap<usize, Vec<usize>> {
+        let mut tree: HashMap<usize, Vec<usize>> = HashMap::new();
+        for block in &self.cfg.blocks {
+            tree.entry(block.start).or_default();
+            if let Some(idom) = self.immediate_dominator(block.start) {
+                tree.entry(idom).or_default().push(block.start);
+            }
+        }
+        for children in tree.values_mut() {
+            children.sort_unstable();
+        }
+        tree
+    }
+
     pub fn dominators_correct(&self) -> bool {
         fn actually_dominates(
             dominators: &DominatorTree,
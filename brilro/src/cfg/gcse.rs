@@ -0,0 +1,45 @@
+use crate::parser::ast::{Instruction, ValueOp};
+
+use super::{
+    analysis::Cfg,
+    data_flow::{AvailableExpressions, Expression, ShimmedCfg},
+};
+
+impl Cfg {
+    /// Global common subexpression elimination.
+    ///
+    /// A computation whose expression is already held in some variable on every incoming path is
+    /// replaced by a copy of that variable, or removed if it would assign the variable to itself.
+    pub fn gcse(&mut self) {
+        let mut available: ShimmedCfg<AvailableExpressions> = ShimmedCfg::from_cfg(self);
+        available.solve_bit_vector();
+        for block in self.blocks.iter_mut() {
+            let mut set = available.inset(block.start).clone().unwrap_or_default();
+            let mut new_instrs = vec![];
+            for insn in &block.instrs {
+                let reusable = Expression::from_instruction(insn).and_then(|e| {
+                    set.iter()
+                        .find(|(avail, _)| *avail == e)
+                        .map(|(_, v)| v.clone())
+                });
+                AvailableExpressions::step(&mut set, insn);
+                match (insn, reusable) {
+                    (Instruction::Value { dest, .. }, Some(var)) if *dest == var => {}
+                    (Instruction::Value { dest, ty, span, .. }, Some(var)) => {
+                        new_instrs.push(Instruction::Value {
+                            op: ValueOp::Id,
+                            dest: dest.clone(),
+                            ty: *ty,
+                            args: vec![var],
+                            funcs: vec![],
+                            labels: vec![],
+                            span: span.clone(),
+                        })
+                    }
+                    _ => new_instrs.push(insn.clone()),
+                }
+            }
+            block.instrs = new_instrs;
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::ast::{EffectOp, Instruction, Literal, Type, ValueOp};
+
+use super::{
+    analysis::{BasicBlock, Cfg, dest},
+    dominator::DominatorTree,
+    lvn::is_terminator,
+};
+
+/// What a definition computes, in terms of the value numbers of its operands.
+#[derive(Debug, PartialEq)]
+enum Key {
+    Constant {
+        ty: Type,
+        value: Literal,
+    },
+    Value {
+        op: ValueOp,
+        ty: Type,
+        args: Vec<String>,
+        funcs: Vec<String>,
+    },
+    /// A `get` or `phi` in a block, identified by the value it takes on each incoming edge.
+    Get {
+        block: usize,
+        incoming: Vec<(usize, String)>,
+    },
+}
+
+#[derive(Debug)]
+struct Gvn {
+    cfg: Cfg,
+    doms: DominatorTree,
+    tree: HashMap<usize, Vec<usize>>,
+    /// Maps every name to the name of the first definition with the same value.
+    numbers: HashMap<String, String>,
+    def_block: HashMap<String, usize>,
+    /// The start of the block with each label.
+    starts: HashMap<String, usize>,
+    /// Scoped by dominance: only entries from dominators of the current block are present.
+    table: Vec<(Key, String)>,
+    removed_gets: HashSet<String>,
+    visited: HashSet<usize>,
+}
+
+impl Gvn {
+    fn new(cfg: &Cfg) -> Self {
+        let doms = DominatorTree::from_cfg(cfg);
+        let def_block = cfg
+            .original_function
+            .args
+            .iter()
+            .map(|a| (a.name.clone(), 0))
+            .collect();
+        let starts = cfg
+            .blocks
+            .iter()
+            .filter_map(|b| b.name.clone().map(|name| (name, b.start)))
+            .collect();
+        Self {
+            cfg: cfg.clone(),
+            tree: doms.tree(),
+            doms,
+            numbers: HashMap::new(),
+            def_block,
+            starts,
+            table: vec![],
+            removed_gets: HashSet::new(),
+            visited: HashSet::new(),
+        }
+    }
+
+    fn number(&self, var: &str) -> String {
+        self.numbers
+            .get(var)
+            .cloned()
+            .unwrap_or_else(|| var.to_string())
+    }
+
+    fn lookup(&self, key: &Key) -> Option<String> {
+        self.table
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    }
+
+    fn last_set<'b>(block: &'b BasicBlock, shadow: &str) -> Option<&'b String> {
+        block.instrs.iter().rev().find_map(|insn| match insn {
+            Instruction::Effect {
+                op: EffectOp::Set,
+                args,
+                ..
+            } if args[0] == shadow => Some(&args[1]),
+            _ => None,
+        })
+    }
+
+    /// The value numbers set for `shadow` along each edge into `block`, if every edge has one.
+    fn incoming(&self, block: usize, shadow: &str) -> Option<Vec<(usize, String)>> {
+        if block == 0 {
+            // The entry block is also reached from outside the function.
+            return None;
+        }
+        self.cfg
+            .block(block)
+            .pred
+            .iter()
+            .map(|&p| Self::last_set(self.cfg.block(p), shadow).map(|x| (p, self.number(x))))
+            .collect()
+    }
+
+    /// The value numbers a `phi` in `block` takes along each edge into it.
+    fn phi_incoming(
+        &self,
+        block: usize,
+        args: &[String],
+        labels: &[String],
+    ) -> Option<Vec<(usize, String)>> {
+        if block == 0 {
+            return None;
+        }
+        let mut incoming: Vec<(usize, String)> = labels
+            .iter()
+            .zip(args)
+            .map(|(label, arg)| (self.starts[label], self.number(arg)))
+            .collect();
+        incoming.sort();
+        Some(incoming)
+    }
+
+    /// If every edge into `block` sets `shadow` to the same value (or to the `get` itself), and
+    /// that value is defined in a strict dominator of `block`, returns its number.
+    fn trivial_get(
+        &self,
+        block: usize,
+        incoming: &[(usize, String)],
+        shadow: &str,
+    ) -> Option<String> {
+        let values: HashSet<&String> = incoming
+            .iter()
+            .map(|(_, v)| v)
+            .filter(|v| *v != shadow)
+            .collect();
+        match values.into_iter().collect::<Vec<_>>()[..] {
+            [v] if self
+                .def_block
+                .get(v)
+                .is_some_and(|&d| d != block && self.doms.dom[&d].contains(&block)) =>
+            {
+                Some(v.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Numbers `dest` by `key`, returning true if an earlier definition already computes it.
+    fn number_def(&mut self, dest: &str, key: Key, block: usize) -> bool {
+        if let Some(v) = self.lookup(&key) {
+            self.numbers.insert(dest.to_string(), v);
+            true
+        } else {
+            self.table.push((key, dest.to_string()));
+            self.def_block.insert(dest.to_string(), block);
+            false
+        }
+    }
+
+    fn visit(&mut self, block_start: usize) {
+        if !self.visited.insert(block_start) {
+            return;
+        }
+        let scope = self.table.len();
+        let mut new_instrs = vec![];
+        for mut insn in self.cfg.block(block_start).instrs.clone() {
+            let is_set = matches!(
+                insn,
+                Instruction::Effect {
+                    op: EffectOp::Set,
+                    ..
+                }
+            );
+            let is_phi = matches!(
+                insn,
+                Instruction::Value {
+                    op: ValueOp::Phi,
+                    ..
+                }
+            );
+            // The arguments of a `phi` are read at the end of its predecessors, some of which may
+            // not have been visited yet, so they are renumbered once the walk is done.
+            if let Instruction::Value { args, .. } | Instruction::Effect { args, .. } = &mut insn
+                && !is_phi
+            {
+                // The first argument of a `set` names a shadow variable rather than a value.
+                for arg in args.iter_mut().skip(usize::from(is_set)) {
+                    *arg = self.number(arg);
+                }
+            }
+            let redundant = match &insn {
+                Instruction::Constant {
+                    dest, ty, value, ..
+                } => {
+                    let key = Key::Constant {
+                        ty: *ty,
+                        value: value.clone(),
+                    };
+                    self.number_def(dest, key, block_start)
+                }
+                Instruction::Value {
+                    op: ValueOp::Id,
+                    dest,
+                    args,
+                    ..
+                } => {
+                    self.numbers.insert(dest.clone(), args[0].clone());
+                    true
+                }
+                Instruction::Value {
+                    op: ValueOp::Get,
+                    dest,
+                    ..
+                } => match self.incoming(block_start, dest) {
+                    Some(incoming) => {
+                        let redundant =
+                            if let Some(v) = self.trivial_get(block_start, &incoming, dest) {
+                                self.numbers.insert(dest.clone(), v);
+                                true
+                            } else {
+                                let key = Key::Get {
+                                    block: block_start,
+                                    incoming,
+                                };
+                                self.number_def(dest, key, block_start)
+                            };
+                        if redundant {
+                            self.removed_gets.insert(dest.clone());
+                        }
+                        redundant
+                    }
+                    None => {
+                        self.def_block.insert(dest.clone(), block_start);
+                        false
+                    }
+                },
+                Instruction::Value {
+                    op: ValueOp::Phi,
+                    dest,
+                    args,
+                    labels,
+                    ..
+                } => match self.phi_incoming(block_start, args, labels) {
+                    Some(incoming) => {
+                        if let Some(v) = self.trivial_get(block_start, &incoming, dest) {
+                            self.numbers.insert(dest.clone(), v);
+                            true
+                        } else {
+                            let key = Key::Get {
+                                block: block_start,
+                                incoming,
+                            };
+                            self.number_def(dest, key, block_start)
+                        }
+                    }
+                    None => {
+                        self.def_block.insert(dest.clone(), block_start);
+                        false
+                    }
+                },
+                Instruction::Value {
+                    op: ValueOp::Call | ValueOp::Undef,
+                    dest,
+                    ..
+                } => {
+                    self.def_block.insert(dest.clone(), block_start);
+                    false
+                }
+                Instruction::Value {
+                    op,
+                    dest,
+                    ty,
+                    args,
+                    funcs,
+                    ..
+                } => {
+                    let mut args = args.clone();
+                    if matches!(
+                        op,
+                        ValueOp::Add | ValueOp::Mul | ValueOp::Eq | ValueOp::And | ValueOp::Or
+                    ) {
+                        args.sort();
+                    }
+                    let key = Key::Value {
+                        op: op.clone(),
+                        ty: *ty,
+                        args,
+                        funcs: funcs.clone(),
+                    };
+                    self.number_def(dest, key, block_start)
+                }
+                Instruction::Effect { .. } | Instruction::Label { .. } => false,
+            };
+            if !redundant {
+                new_instrs.push(insn);
+            }
+        }
+        self.cfg.block_mut(block_start).instrs = new_instrs;
+
+        for child in self.tree[&block_start].clone() {
+            self.visit(child);
+        }
+        self.table.truncate(scope);
+    }
+
+    fn cfg(mut self) -> Cfg {
+        let removed_gets = self.removed_gets;
+        // The `phi`s at the head of a block run one after the other, so an argument can't be
+        // numbered as another `phi` of the same block, which may already have its new value.
+        // Such arguments keep their name and get a copy at the end of their predecessor instead.
+        let mut copies: Vec<(usize, Instruction)> = vec![];
+        for block in &mut self.cfg.blocks {
+            let phis: HashSet<String> = block
+                .instrs
+                .iter()
+                .filter_map(|insn| match insn {
+                    Instruction::Value {
+                        op: ValueOp::Phi,
+                        dest,
+                        ..
+                    } => Some(dest.clone()),
+                    _ => None,
+                })
+                .collect();
+            for insn in &mut block.instrs {
+                if let Instruction::Value {
+                    op: ValueOp::Phi,
+                    dest: phi,
+                    ty,
+                    args,
+                    labels,
+                    ..
+                } = insn
+                {
+                    for (arg, label) in args.iter_mut().zip(labels.iter()) {
+                        let Some(number) = self.numbers.get(arg) else {
+                            continue;
+                        };
+                        if number == phi || !phis.contains(number) {
+                            *arg = number.clone();
+                        } else if !copies.iter().any(|(_, c)| dest(c) == Some(arg)) {
+                            let copy = Instruction::Value {
+                                op: ValueOp::Id,
+                                dest: arg.clone(),
+                                ty: *ty,
+                                args: vec![number.clone()],
+                                funcs: vec![],
+                                labels: vec![],
+                                span: None,
+                            };
+                            copies.push((self.starts[label], copy));
+                        }
+                    }
+                }
+            }
+            block.instrs.retain(|insn| {
+                !matches!(
+                    insn,
+                    Instruction::Effect {
+                        op: EffectOp::Set,
+                        args,
+                        ..
+                    } if removed_gets.contains(&args[0])
+                )
+            });
+        }
+        for (pred, copy) in copies {
+            let instrs = &mut self.cfg.block_mut(pred).instrs;
+            let at = instrs.len() - usize::from(instrs.last().is_some_and(is_terminator));
+            instrs.insert(at, copy);
+        }
+        self.cfg
+    }
+}
+
+/// Dominator-based global value numbering over a function in SSA form.
+///
+/// Walks the dominator tree in preorder, so a value computed in a block is reused by every block
+/// it dominates. Copies are folded away, and a `get` is removed when every incoming `set` carries
+/// the same value or when another `get` in the same block receives the same values. A `phi` is
+/// removed in the same cases, with its arguments standing in for the incoming `set`s.
+///
+/// Values flowing around back edges aren't numbered when a loop header is visited, so the walk
+/// is repeated until nothing changes.
+pub fn gvn(cfg: &Cfg) -> Cfg {
+    let mut cfg = cfg.clone();
+    loop {
+        let mut gvn = Gvn::new(&cfg);
+        for block in cfg.blocks.iter().map(|b| b.start) {
+            if gvn.doms.immediate_dominator(block).is_none() {
+                gvn.visit(block);
+            }
+        }
+        let next = gvn.cfg();
+        if next.function().instrs == cfg.function().instrs {
+            return next;
+        }
+        cfg = next;
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::ast::{Instruction, Type, ValueOp};
+
+use super::{
+    analysis::{Cfg, Position, dest, uses},
+    loops::{LoopForest, NaturalLoop},
+    lvn::is_terminator,
+    purity::Purity,
+};
+
+/// A variable updated exactly once in a loop, by `var = var + step` or `var = var - step`.
+#[derive(Debug, Clone)]
+pub struct BasicIv {
+    pub var: String,
+    /// Loop invariant.
+    pub step: String,
+    /// The update subtracts `step`.
+    pub negate: bool,
+    pub def: Position,
+}
+
+/// A variable defined exactly once in a loop, holding `iv * scale + offset` where it's defined.
+#[derive(Debug, Clone)]
+pub struct DerivedIv {
+    pub var: String,
+    /// The basic induction variable this is derived from.
+    pub iv: String,
+    /// Loop invariant factors, multiplied together.
+    pub scale: Vec<String>,
+    /// Products of loop invariant factors, subtracted if the flag is set.
+    pub offset: Vec<(bool, Vec<String>)>,
+    pub def: Position,
+}
+
+impl DerivedIv {
+    fn basic(iv: &str) -> Self {
+        Self {
+            var: iv.to_string(),
+            iv: iv.to_string(),
+            scale: vec![],
+            offset: vec![],
+            def: (0, 0),
+        }
+    }
+}
+
+/// The induction variables of a single loop.
+#[derive(Debug, Clone)]
+pub struct Induction {
+    pub basic: Vec<BasicIv>,
+    pub derived: Vec<DerivedIv>,
+}
+
+impl Induction {
+    pub fn from_loop(cfg: &Cfg, l: &NaturalLoop) -> Self {
+        let positions: Vec<Position> = l
+            .body
+            .iter()
+            .flat_map(|&b| (0..cfg.block(b).instrs.len()).map(move |i| (b, i)))
+            .collect();
+        let insn = |(b, i): Position| &cfg.block(b).instrs[i];
+        let mut defs: HashMap<&String, Vec<Position>> = HashMap::new();
+        for &p in &positions {
+            if let Some(d) = dest(insn(p)) {
+                defs.entry(d).or_default().push(p);
+            }
+        }
+        let invariant = |v: &String| !defs.contains_key(v);
+        let defined_once = |v: &String| defs.get(v).is_some_and(|d| d.len() == 1);
+
+        let basic: Vec<BasicIv> = positions
+            .iter()
+            .filter_map(|&p| match insn(p) {
+                Instruction::Value {
+                    op: op @ (ValueOp::Add | ValueOp::Sub),
+                    dest,
+                    args,
+                    ..
+                } if defined_once(dest) => {
+                    let step = match &args[..] {
+                        [a, s] if a == dest => s,
+                        [s, a] if a == dest && *op == ValueOp::Add => s,
+                        _ => return None,
+                    };
+                    invariant(step).then(|| BasicIv {
+                        var: dest.clone(),
+                        step: step.clone(),
+                        negate: *op == ValueOp::Sub,
+                        def: p,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+
+        // A derived variable is only known to match its formula right after it's defined, so
+        // chains of them are followed within a block while nothing they depend on changes.
+        let mut derived = vec![];
+        for &b in &l.body {
+            let mut current: HashMap<String, DerivedIv> = basic
+                .iter()
+                .map(|iv| (iv.var.clone(), DerivedIv::basic(&iv.var)))
+                .collect();
+            for (i, insn) in cfg.block(b).instrs.iter().enumerate() {
+                let family = match insn {
+                    Instruction::Value {
+                        op: op @ (ValueOp::Add | ValueOp::Sub | ValueOp::Mul),
+                        dest,
+                        args,
+                        ..
+                    } if defined_once(dest) && !basic.iter().any(|iv| iv.var == *dest) => {
+                        let operands = match &args[..] {
+                            [x, k] if current.contains_key(x) && invariant(k) => Some((x, k)),
+                            [k, x]
+                                if current.contains_key(x)
+                                    && invariant(k)
+                                    && *op != ValueOp::Sub =>
+                            {
+                                Some((x, k))
+                            }
+                            _ => None,
+                        };
+                        operands.map(|(x, k)| {
+                            let mut family = current[x].clone();
+                            match op {
+                                ValueOp::Mul => {
+                                    family.scale.push(k.clone());
+                                    for (_, factors) in &mut family.offset {
+                                        factors.push(k.clone());
+                                    }
+                                }
+                                _ => family.offset.push((*op == ValueOp::Sub, vec![k.clone()])),
+                            }
+                            family.var = dest.clone();
+                            family.def = (b, i);
+                            family
+                        })
+                    }
+                    _ => None,
+                };
+                if let Some(d) = dest(insn) {
+                    current.retain(|v, family| v != d && family.iv != *d);
+                    if basic.iter().any(|iv| iv.var == *d) {
+                        current.insert(d.clone(), DerivedIv::basic(d));
+                    }
+                }
+                if let Some(family) = family {
+                    current.insert(family.var.clone(), family.clone());
+                    derived.push(family);
+                }
+            }
+        }
+
+        Self { basic, derived }
+    }
+}
+
+impl Cfg {
+    /// Strength reduction of derived induction variables.
+    ///
+    /// A derived variable involving a multiplication is replaced by a copy of a new variable,
+    /// initialized in the preheader and bumped right after its basic induction variable rather
+    /// than in the latch, so it matches its formula everywhere in the loop, including derived
+    /// variables defined after the update. Derived variables only used to compute other derived
+    /// variables are left for dce. Unless `all` is set, a reduction is only made if it leaves
+    /// fewer instructions in the loop once that's done.
+    pub fn strength_reduce(&mut self, all: bool) {
+        let mut preheaders = vec![];
+        while let Some(label) = self.insert_preheader() {
+            preheaders.push(label);
+        }
+        while self.reduce_derived(!all) {}
+        self.remove_forwarding_blocks(&preheaders);
+    }
+
+    /// Reduces a derived variable of the innermost loop which has one, returning false if none
+    /// are left.
+    fn reduce_derived(&mut self, only_cheaper: bool) -> bool {
+        let forest = LoopForest::from_cfg(self);
+        let mut order: Vec<&NaturalLoop> = forest.loops.iter().collect();
+        order.sort_by_key(|l| std::cmp::Reverse(l.depth));
+        for l in order {
+            let induction = Induction::from_loop(self, l);
+            let preheader = *self
+                .block(l.header)
+                .pred
+                .iter()
+                .find(|p| !l.body.contains(p))
+                .unwrap();
+
+            // The new variable starts out in the preheader, so whatever it reads there has to be
+            // assigned on every path into the loop.
+            let defined = self.defined_after(preheader);
+
+            let derived_defs: HashSet<Position> = induction.derived.iter().map(|d| d.def).collect();
+            let candidates = induction.derived.iter().filter(|d| {
+                let iv = induction.basic.iter().find(|iv| iv.var == d.iv).unwrap();
+                let needed = self.blocks.iter().any(|b| {
+                    b.instrs.iter().enumerate().any(|(i, insn)| {
+                        !derived_defs.contains(&(b.start, i)) && uses(insn).contains(&d.var)
+                    })
+                });
+                let mut reads = d
+                    .scale
+                    .iter()
+                    .chain(d.offset.iter().flat_map(|(_, f)| f))
+                    .chain([&d.iv, &iv.step]);
+                // A variable read after the loop keeps its copy of the reduced one, which costs
+                // as much as the multiplication it replaces.
+                let live_out = only_cheaper && self.live_at(l.exits.iter().copied(), &d.var);
+                needed && !live_out && !d.scale.is_empty() && reads.all(|v| defined.contains(v))
+            });
+            let cost = only_cheaper.then(|| self.loop_cost(&forest, l));
+            for root in candidates {
+                let mut reduced = self.clone();
+                reduced.reduce(&induction, root, preheader);
+                if cost
+                    .as_ref()
+                    .is_none_or(|cost| reduced.loop_cost(&forest, l) < *cost)
+                {
+                    *self = reduced;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Replaces `root` by a new variable stepped along with its basic induction variable.
+    fn reduce(&mut self, induction: &Induction, root: &DerivedIv, preheader: usize) {
+        let iv = induction.basic.iter().find(|iv| iv.var == root.iv).unwrap();
+
+        let reduced = self.fresh_var(&format!("{}.sr", root.var));
+        let step = self.fresh_var(&format!("{}.step", root.var));
+        let term = self.fresh_var(&format!("{}.term", root.var));
+        let mut init = vec![value(ValueOp::Mul, &reduced, &root.iv, &root.scale[0])];
+        for k in &root.scale[1..] {
+            init.push(value(ValueOp::Mul, &reduced, &reduced, k));
+        }
+        for (negate, factors) in &root.offset {
+            let op = if *negate { ValueOp::Sub } else { ValueOp::Add };
+            if let [f] = &factors[..] {
+                init.push(value(op, &reduced, &reduced, f));
+            } else {
+                init.push(value(ValueOp::Mul, &term, &factors[0], &factors[1]));
+                for f in &factors[2..] {
+                    init.push(value(ValueOp::Mul, &term, &term, f));
+                }
+                init.push(value(op, &reduced, &reduced, &term));
+            }
+        }
+        init.push(value(ValueOp::Mul, &step, &iv.step, &root.scale[0]));
+        for k in &root.scale[1..] {
+            init.push(value(ValueOp::Mul, &step, &step, k));
+        }
+
+        let (b, i) = root.def;
+        let def = &mut self.block_mut(b).instrs[i];
+        let span = match def {
+            Instruction::Value { span, .. } => span.clone(),
+            _ => None,
+        };
+        *def = Instruction::Value {
+            op: ValueOp::Id,
+            dest: root.var.clone(),
+            ty: Type::Int,
+            args: vec![reduced.clone()],
+            funcs: vec![],
+            labels: vec![],
+            span,
+        };
+        let bump = if iv.negate {
+            ValueOp::Sub
+        } else {
+            ValueOp::Add
+        };
+        let (b, i) = iv.def;
+        self.block_mut(b)
+            .instrs
+            .insert(i + 1, value(bump, &reduced, &reduced, &step));
+
+        let instrs = &mut self.block_mut(preheader).instrs;
+        let at = instrs.len() - usize::from(instrs.last().is_some_and(is_terminator));
+        instrs.splice(at..at, init);
+    }
+
+    /// The instructions left in the body of `l` once copies are propagated and dead code is
+    /// removed, standing for what an iteration costs.
+    ///
+    /// Instructions are counted per loop depth, deepest first, since those run the most.
+    fn loop_cost(&self, forest: &LoopForest, l: &NaturalLoop) -> Vec<usize> {
+        let mut cfg = self.clone();
+        cfg.copy_propagation();
+        cfg.dce(&Purity::default());
+        let depth = |b: usize| {
+            forest
+                .loops
+                .iter()
+                .filter(|m| m.body.contains(&b))
+                .map(|m| m.depth)
+                .max()
+                .unwrap()
+        };
+        let deepest = l.body.iter().map(|&b| depth(b)).max().unwrap();
+        let mut cost = vec![0; deepest - l.depth + 1];
+        for &b in &l.body {
+            let insns = cfg.block(b).instrs.iter();
+            cost[deepest - depth(b)] += insns
+                .filter(|insn| !matches!(insn, Instruction::Label { .. }))
+                .count();
+        }
+        cost
+    }
+}
+
+fn value(op: ValueOp, dest: &str, a: &str, b: &str) -> Instruction {
+    Instruction::Value {
+        op,
+        dest: dest.to_string(),
+        ty: Type::Int,
+        args: vec![a.to_string(), b.to_string()],
+        funcs: vec![],
+        labels: vec![],
+        span: None,
+    }
+}
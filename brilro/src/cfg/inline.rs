@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::ast::{EffectOp, Function, Instruction, Program, Type, ValueOp};
+
+use super::analysis::{fresh_name, id, jump, mentioned, mentions};
+
+/// Callees with more instructions than this are never inlined.
+const INLINE_SIZE_LIMIT: usize = 32;
+
+/// Calls which only appear because of this many levels of inlining are left alone, so inlining
+/// recursive functions terminates.
+const MAX_INLINE_DEPTH: usize = 3;
+
+/// The number of instructions `f` runs in its body, ignoring labels.
+fn size(f: &Function) -> usize {
+    f.instrs
+        .iter()
+        .filter(|insn| !matches!(insn, Instruction::Label { .. }))
+        .count()
+}
+
+/// Names already used in the function being inlined into.
+struct Names {
+    vars: HashSet<String>,
+    labels: HashSet<String>,
+}
+
+impl Names {
+    fn new(f: &Function) -> Self {
+        let mut vars: HashSet<String> = f.args.iter().map(|a| a.name.clone()).collect();
+        let mut labels = HashSet::new();
+        for insn in &f.instrs {
+            if let Instruction::Label { label, .. } = insn {
+                labels.insert(label.clone());
+            }
+            vars.extend(mentioned(insn).into_iter().cloned());
+        }
+        Self { vars, labels }
+    }
+
+    fn fresh(taken: &mut HashSet<String>, base: String) -> String {
+        let name = fresh_name(&base, |name| taken.contains(name));
+        taken.insert(name.clone());
+        name
+    }
+
+    fn fresh_var(&mut self, base: String) -> String {
+        Self::fresh(&mut self.vars, base)
+    }
+
+    fn fresh_label(&mut self, base: String) -> String {
+        Self::fresh(&mut self.labels, base)
+    }
+}
+
+/// Function inlining.
+///
+/// Calls to functions of at most `INLINE_SIZE_LIMIT` instructions are replaced by a copy of the
+/// callee's body, with its variables and labels renamed to avoid clashes. The parameters are
+/// assigned the arguments on entry, and each `ret` becomes an assignment to the destination of
+/// the call and a jump past the copy. Calls appearing in inlined code are inlined too, up to
+/// `MAX_INLINE_DEPTH` levels deep.
+pub fn inline(prog: &mut Program) {
+    let callees: HashMap<String, Function> = prog
+        .functions
+        .iter()
+        .filter(|f| size(f) <= INLINE_SIZE_LIMIT)
+        .map(|f| (f.name.clone(), f.clone()))
+        .collect();
+    for f in &mut prog.functions {
+        inline_calls(f, &callees);
+    }
+}
+
+fn inline_calls(f: &mut Function, callees: &HashMap<String, Function>) {
+    let mut names = Names::new(f);
+    // Each instruction with the number of calls inlined to get it here.
+    let mut instrs: Vec<(Instruction, usize)> = f.instrs.drain(..).map(|i| (i, 0)).collect();
+    let mut i = 0;
+    while i < instrs.len() {
+        let (insn, depth) = &instrs[i];
+        let (dest, args, funcs) = match insn {
+            Instruction::Value {
+                op: ValueOp::Call,
+                dest,
+                ty,
+                args,
+                funcs,
+                ..
+            } => (Some((dest.clone(), *ty)), args, funcs),
+            Instruction::Effect {
+                op: EffectOp::Call,
+                args,
+                funcs,
+                ..
+            } => (None, args, funcs),
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        let Some(callee) = callees.get(&funcs[0]).filter(|_| *depth < MAX_INLINE_DEPTH) else {
+            i += 1;
+            continue;
+        };
+        let depth = depth + 1;
+        let body = copy_body(callee, args, dest, &mut names);
+        instrs.splice(i..=i, body.into_iter().map(|insn| (insn, depth)));
+    }
+    f.instrs = instrs.into_iter().map(|(insn, _)| insn).collect();
+}
+
+/// The instructions replacing a call to `callee` with the given arguments, storing the result
+/// to `dest`.
+fn copy_body(
+    callee: &Function,
+    args: &[String],
+    dest: Option<(String, Type)>,
+    names: &mut Names,
+) -> Vec<Instruction> {
+    let mut renames: HashMap<String, String> = HashMap::new();
+    let mut relabels: HashMap<String, String> = HashMap::new();
+    for insn in &callee.instrs {
+        if let Instruction::Label { label, .. } = insn {
+            let new = names.fresh_label(format!("{}.{label}", callee.name));
+            relabels.insert(label.clone(), new);
+        }
+    }
+    let params = callee.args.iter().map(|a| &a.name);
+    let locals = callee.instrs.iter().flat_map(mentioned);
+    for var in params.chain(locals) {
+        if !renames.contains_key(var) {
+            let new = names.fresh_var(format!("{}.{var}", callee.name));
+            renames.insert(var.clone(), new);
+        }
+    }
+    let done = names.fresh_label(format!("{}.ret", callee.name));
+
+    let mut out: Vec<Instruction> = callee
+        .args
+        .iter()
+        .zip(args)
+        .map(|(param, arg)| id(renames[&param.name].clone(), param.ty, arg.clone(), None))
+        .collect();
+    for insn in &callee.instrs {
+        let mut insn = insn.clone();
+        for var in mentions(&mut insn) {
+            *var = renames[var].clone();
+        }
+        match &mut insn {
+            Instruction::Label { label, .. } => *label = relabels[label].clone(),
+            Instruction::Effect {
+                op: EffectOp::Jmp | EffectOp::Br,
+                labels,
+                ..
+            } => {
+                for label in labels.iter_mut() {
+                    *label = relabels[label].clone();
+                }
+            }
+            Instruction::Effect {
+                op: EffectOp::Ret,
+                args,
+                ..
+            } => {
+                if let (Some((dest, ty)), [value]) = (&dest, &args[..]) {
+                    out.push(id(dest.clone(), *ty, value.clone(), None));
+                }
+                out.push(jump(done.clone()));
+                continue;
+            }
+            _ => {}
+        }
+        out.push(insn);
+    }
+    // The last `ret` would jump straight to the next instruction.
+    if matches!(out.last(), Some(Instruction::Effect { op: EffectOp::Jmp, labels, .. }) if labels[0] == done)
+    {
+        out.pop();
+    }
+    out.push(Instruction::Label {
+        label: done,
+        span: None,
+    });
+    out
+}
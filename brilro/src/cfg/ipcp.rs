@@ -0,0 +1,142 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::parser::ast::{ConstOps, EffectOp, Function, Instruction, Literal, Program, ValueOp};
+
+use super::{
+    analysis::{Cfg, Position},
+    call_graph::CallGraph,
+    data_flow::{ReachingDefinitions, ShimmedCfg},
+};
+
+/// What is known about an argument passed at a call site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Known {
+    Constant(Literal),
+    /// The caller's own parameter, unchanged since entry.
+    Param(String),
+    Unknown,
+}
+
+/// A call site: the callee and what is known about each argument.
+type CallSite = (String, Vec<Known>);
+
+/// The call sites in `f`, with the arguments which are constants or untouched parameters.
+fn call_sites(f: &Function) -> Vec<CallSite> {
+    // The parameters get a definition of their own at the top, so that reaching definitions can
+    // tell when a parameter still holds the value it was passed.
+    let mut with_params = f.clone();
+    with_params.instrs.splice(
+        0..0,
+        f.args.iter().map(|a| Instruction::Value {
+            op: ValueOp::Undef,
+            dest: a.name.clone(),
+            ty: a.ty,
+            args: vec![],
+            funcs: vec![],
+            labels: vec![],
+            span: None,
+        }),
+    );
+    let cfg = Cfg::from_function(&with_params);
+    let mut solved: ShimmedCfg<ReachingDefinitions> = ShimmedCfg::from_cfg(&cfg);
+    solved.solve_bit_vector();
+
+    let known = |reaching: &BTreeSet<(Position, String)>, var: &String| {
+        let mut defs = reaching.iter().filter(|(_, v)| v == var);
+        let (Some(((def_block, def_idx), _)), None) = (defs.next(), defs.next()) else {
+            return Known::Unknown;
+        };
+        match &cfg.block(*def_block).instrs[*def_idx] {
+            Instruction::Constant { value, .. } => Known::Constant(value.clone()),
+            _ if *def_block == 0 && *def_idx < f.args.len() => Known::Param(var.clone()),
+            _ => Known::Unknown,
+        }
+    };
+
+    let mut sites = vec![];
+    for block in &cfg.blocks {
+        let mut reaching = solved.inset(block.start).clone();
+        for (idx, insn) in block.instrs.iter().enumerate() {
+            if let Instruction::Value {
+                op: ValueOp::Call,
+                args,
+                funcs,
+                ..
+            }
+            | Instruction::Effect {
+                op: EffectOp::Call,
+                args,
+                funcs,
+                ..
+            } = insn
+            {
+                let args = args.iter().map(|a| known(&reaching, a)).collect();
+                sites.push((funcs[0].clone(), args));
+            }
+            ReachingDefinitions::step(&mut reaching, (block.start, idx), insn);
+        }
+    }
+    sites
+}
+
+/// Interprocedural constant propagation of arguments.
+///
+/// When a parameter of a function other than `main` gets the same constant at every call site,
+/// a `const` for it is put at the top of the function so the body can be folded further. A
+/// recursive call which passes the parameter along unchanged doesn't count against it. Repeats
+/// until nothing changes, since a parameter becoming constant can make the arguments of calls
+/// in its function constant.
+pub fn propagate_constant_args(prog: &mut Program) {
+    let graph = CallGraph::from_program(prog);
+    let mut specialized: BTreeSet<(String, String)> = BTreeSet::new();
+    loop {
+        let sites: HashMap<String, Vec<CallSite>> = prog
+            .functions
+            .iter()
+            .map(|f| (f.name.clone(), call_sites(f)))
+            .collect();
+        let mut consts = vec![];
+        for callee in prog.functions.iter().filter(|f| f.name != "main") {
+            let calls: Vec<(&String, &Vec<Known>)> = graph
+                .callers(&callee.name)
+                .flat_map(|caller| {
+                    sites[caller]
+                        .iter()
+                        .filter(|(name, _)| *name == callee.name)
+                        .map(move |(_, args)| (caller, args))
+                })
+                .collect();
+            for (i, param) in callee.args.iter().enumerate() {
+                if specialized.contains(&(callee.name.clone(), param.name.clone())) {
+                    continue;
+                }
+                let mut value = None;
+                let agree = calls.iter().all(|(caller, args)| match &args[i] {
+                    Known::Param(p) if **caller == callee.name && *p == param.name => true,
+                    Known::Constant(c) => *value.get_or_insert(c) == c,
+                    Known::Param(_) | Known::Unknown => false,
+                });
+                if let (true, Some(value)) = (agree, value) {
+                    consts.push((callee.name.clone(), param.clone(), value.clone()));
+                }
+            }
+        }
+        if consts.is_empty() {
+            break;
+        }
+        for (name, param, value) in consts {
+            let f = prog.functions.iter_mut().find(|f| f.name == name).unwrap();
+            f.instrs.insert(
+                0,
+                Instruction::Constant {
+                    op: ConstOps::Const,
+                    dest: param.name.clone(),
+                    ty: param.ty,
+                    value,
+                    span: None,
+                },
+            );
+            specialized.insert((name, param.name));
+        }
+    }
+}
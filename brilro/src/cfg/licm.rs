@@ -0,0 +1,248 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::parser::ast::{Instruction, Literal, ValueOp};
+
+use super::{
+    analysis::{Cfg, Position, dest, uses},
+    data_flow::{ReachingDefinitions, ShimmedCfg},
+    dominator::DominatorTree,
+    loops::{LoopForest, NaturalLoop},
+    lvn::is_terminator,
+};
+
+struct Licm<'a> {
+    cfg: &'a Cfg,
+    doms: &'a DominatorTree,
+    l: &'a NaturalLoop,
+    /// The definitions reaching each instruction in the loop.
+    reaching: HashMap<Position, BTreeSet<(Position, String)>>,
+    /// The variables assigned on every path into the loop, which hoisted code can read.
+    defined: BTreeSet<String>,
+}
+
+impl<'a> Licm<'a> {
+    fn new(
+        cfg: &'a Cfg,
+        doms: &'a DominatorTree,
+        solved: &ShimmedCfg<ReachingDefinitions>,
+        l: &'a NaturalLoop,
+    ) -> Self {
+        let mut reaching = HashMap::new();
+        for &b in &l.body {
+            let len = cfg.block(b).instrs.len();
+            for (idx, defs) in solved.facts(b).into_iter().take(len).enumerate() {
+                reaching.insert((b, idx), defs);
+            }
+        }
+        let preheader = *cfg
+            .block(l.header)
+            .pred
+            .iter()
+            .find(|p| !l.body.contains(p))
+            .unwrap();
+        Self {
+            cfg,
+            doms,
+            l,
+            reaching,
+            defined: cfg.defined_after(preheader),
+        }
+    }
+
+    /// The definitions of `var` which reach the instruction at `pos`.
+    fn reaching_defs(&self, pos: Position, var: &str) -> Vec<Position> {
+        self.reaching[&pos]
+            .iter()
+            .filter(|(_, v)| v == var)
+            .map(|(def, _)| *def)
+            .collect()
+    }
+
+    /// The position of the only definition of `var` reaching `pos`, if there is exactly one.
+    fn only_def(&self, pos: Position, var: &str) -> Option<Position> {
+        match self.reaching_defs(pos, var)[..] {
+            [def] => Some(def),
+            _ => None,
+        }
+    }
+
+    fn insn(&self, (b, i): Position) -> &Instruction {
+        &self.cfg.block(b).instrs[i]
+    }
+
+    fn is_invariant(&self, pos: Position, invariant: &[Position]) -> bool {
+        let insn = self.insn(pos);
+        let args = match insn {
+            Instruction::Constant { .. } => return true,
+            Instruction::Value {
+                op: ValueOp::Call | ValueOp::Get | ValueOp::Undef | ValueOp::Phi,
+                ..
+            }
+            | Instruction::Effect { .. }
+            | Instruction::Label { .. } => return false,
+            Instruction::Value { args, .. } => args,
+        };
+
+        // Hoisting a division must not introduce a trap on a path which never divided.
+        if let Instruction::Value {
+            op: ValueOp::Div, ..
+        } = insn
+        {
+            let nonzero = self.only_def(pos, &args[1]).is_some_and(|def| {
+                matches!(
+                    self.insn(def),
+                    Instruction::Constant { value: Literal::Int(v), .. } if *v != 0
+                )
+            });
+            if !nonzero {
+                return false;
+            }
+        }
+
+        args.iter().all(|arg| {
+            let defs = self.reaching_defs(pos, arg);
+            defs.iter().all(|(b, _)| !self.l.body.contains(b))
+                || self
+                    .only_def(pos, arg)
+                    .is_some_and(|def| invariant.contains(&def))
+        })
+    }
+
+    /// The loop invariant instructions, ordered so each comes after those it depends on.
+    fn invariants(&self) -> Vec<Position> {
+        let mut invariant = vec![];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in &self.l.body {
+                for i in 0..self.cfg.block(b).instrs.len() {
+                    if !invariant.contains(&(b, i)) && self.is_invariant((b, i), &invariant) {
+                        invariant.push((b, i));
+                        changed = true;
+                    }
+                }
+            }
+        }
+        invariant
+    }
+
+    fn can_hoist(&self, pos: Position) -> bool {
+        let var = dest(self.insn(pos)).unwrap();
+        let positions = || {
+            self.l
+                .body
+                .iter()
+                .flat_map(|&b| (0..self.cfg.block(b).instrs.len()).map(move |i| (b, i)))
+        };
+
+        let defined_once = positions()
+            .filter(|&p| dest(self.insn(p)) == Some(var))
+            .count()
+            == 1;
+        // Every use in the loop must see only this definition. Reaching definitions don't account
+        // for the value a parameter enters with, so the variable mustn't be live into the loop
+        // either.
+        let reaches_all_uses = positions()
+            .filter(|&p| uses(self.insn(p)).contains(var))
+            .all(|p| self.reaching_defs(p, var) == [pos])
+            && !self.cfg.live_at([self.l.header], var);
+        // Either the definition runs before leaving the loop anyway, or nobody outside can tell.
+        let dominates_exits = self
+            .l
+            .body
+            .iter()
+            .filter(|&&b| {
+                self.cfg
+                    .block(b)
+                    .flows_to
+                    .iter()
+                    .any(|s| !self.l.body.contains(s))
+            })
+            .all(|b| self.doms.dom[&pos.0].contains(b));
+
+        defined_once
+            && reaches_all_uses
+            && (dominates_exits || !self.cfg.live_at(self.l.exits.iter().copied(), var))
+    }
+
+    /// The instructions which can be moved to the preheader, in the order to put them there.
+    fn hoistable(&self) -> Vec<Position> {
+        let mut hoisted: Vec<Position> = vec![];
+        for pos in self.invariants() {
+            // An argument from outside the loop has to be assigned on every way into it, or the
+            // preheader could read it on a path where the loop never would.
+            let args_hoisted = uses(self.insn(pos)).iter().all(|arg| {
+                (self
+                    .reaching_defs(pos, arg)
+                    .iter()
+                    .all(|(b, _)| !self.l.body.contains(b))
+                    && self.defined.contains(arg))
+                    || self
+                        .only_def(pos, arg)
+                        .is_some_and(|def| hoisted.contains(&def))
+            });
+            if args_hoisted && self.can_hoist(pos) {
+                hoisted.push(pos);
+            }
+        }
+        hoisted
+    }
+}
+
+impl Cfg {
+    /// Loop-invariant code motion.
+    ///
+    /// Loops are first given preheaders to hoist into. An instruction is invariant when the
+    /// definitions of its arguments reaching it are all outside the loop, or are a single
+    /// invariant instruction. Invariant instructions are moved to the preheader when that
+    /// can't change what any use sees, and what they read from outside the loop is assigned on
+    /// every path into it. Calls and divisions which may trap are never moved.
+    /// Preheaders nothing was hoisted into are removed again.
+    pub fn licm(&mut self) {
+        let mut preheaders = vec![];
+        while let Some(label) = self.insert_preheader() {
+            preheaders.push(label);
+        }
+        while self.hoist_invariants() {}
+        self.remove_forwarding_blocks(&preheaders);
+    }
+
+    /// Hoists the invariant instructions of the innermost loop which has any, returning false if
+    /// none do.
+    fn hoist_invariants(&mut self) -> bool {
+        let doms = DominatorTree::from_cfg(self);
+        let forest = LoopForest::from_cfg_and_doms(self, &doms);
+        let mut solved: ShimmedCfg<ReachingDefinitions> = ShimmedCfg::from_cfg(self);
+        solved.solve_bit_vector();
+
+        let mut order: Vec<&NaturalLoop> = forest.loops.iter().collect();
+        order.sort_by_key(|l| std::cmp::Reverse(l.depth));
+        for l in order {
+            let hoisted = Licm::new(self, &doms, &solved, l).hoistable();
+            if hoisted.is_empty() {
+                continue;
+            }
+
+            let preheader = *self
+                .block(l.header)
+                .pred
+                .iter()
+                .find(|p| !l.body.contains(p))
+                .unwrap();
+            let moved: Vec<Instruction> = hoisted
+                .iter()
+                .map(|&(b, i)| self.block(b).instrs[i].clone())
+                .collect();
+            let mut removed = hoisted.clone();
+            removed.sort_unstable();
+            for &(b, i) in removed.iter().rev() {
+                self.block_mut(b).instrs.remove(i);
+            }
+            let instrs = &mut self.block_mut(preheader).instrs;
+            let at = instrs.len() - usize::from(instrs.last().is_some_and(is_terminator));
+            instrs.splice(at..at, moved);
+            return true;
+        }
+        false
+    }
+}
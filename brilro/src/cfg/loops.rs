@@ -0,0 +1,206 @@
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+
+use super::{analysis::Cfg, dominator::DominatorTree};
+
+/// A natural loop, with blocks identified by their start.
+#[derive(Debug, Clone, Serialize)]
+pub struct NaturalLoop {
+    pub header: usize,
+    /// Every block in the loop, including the header.
+    pub body: BTreeSet<usize>,
+    /// Blocks in the loop with a back edge to the header.
+    pub latches: BTreeSet<usize>,
+    /// Blocks outside the loop reached by an edge from inside it.
+    pub exits: BTreeSet<usize>,
+    /// Index of the innermost loop containing this one.
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    /// Outermost loops have depth 1.
+    pub depth: usize,
+}
+
+/// All natural loops of a function, ordered by header, along with how they nest.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoopForest {
+    pub loops: Vec<NaturalLoop>,
+}
+
+impl LoopForest {
+    pub fn from_cfg(cfg: &Cfg) -> Self {
+        Self::from_cfg_and_doms(cfg, &DominatorTree::from_cfg(cfg))
+    }
+
+    pub fn from_cfg_and_doms(cfg: &Cfg, doms: &DominatorTree) -> Self {
+        let reachable = cfg.reachable();
+
+        // Loops sharing a header are merged, so find all the latches of each header first.
+        let mut latches: Vec<(usize, BTreeSet<usize>)> = vec![];
+        for block in cfg.blocks.iter().filter(|b| reachable.contains(&b.start)) {
+            for &succ in &block.flows_to {
+                if doms.dom[&succ].contains(&block.start) {
+                    match latches.iter_mut().find(|(h, _)| *h == succ) {
+                        Some((_, l)) => {
+                            l.insert(block.start);
+                        }
+                        None => latches.push((succ, BTreeSet::from([block.start]))),
+                    }
+                }
+            }
+        }
+        latches.sort_unstable();
+
+        let mut loops: Vec<NaturalLoop> = latches
+            .into_iter()
+            .map(|(header, latches)| {
+                let mut body = BTreeSet::from([header]);
+                let mut stack: Vec<usize> = latches.iter().copied().collect();
+                while let Some(b) = stack.pop() {
+                    if body.insert(b) {
+                        stack.extend(cfg.block(b).pred.iter().filter(|p| reachable.contains(p)));
+                    }
+                }
+                let exits = body
+                    .iter()
+                    .flat_map(|&b| cfg.block(b).flows_to.iter().copied())
+                    .filter(|s| !body.contains(s))
+                    .collect();
+                NaturalLoop {
+                    header,
+                    body,
+                    latches,
+                    exits,
+                    parent: None,
+                    children: vec![],
+                    depth: 1,
+                }
+            })
+            .collect();
+
+        // The parent of a loop is the smallest other loop containing it.
+        for i in 0..loops.len() {
+            loops[i].parent = (0..loops.len())
+                .filter(|&j| j != i && loops[j].body.is_superset(&loops[i].body))
+                .min_by_key(|&j| loops[j].body.len());
+        }
+        for i in 0..loops.len() {
+            if let Some(p) = loops[i].parent {
+                loops[p].children.push(i);
+            }
+            let mut depth = 1;
+            let mut cur = loops[i].parent;
+            while let Some(p) = cur {
+                depth += 1;
+                cur = loops[p].parent;
+            }
+            loops[i].depth = depth;
+        }
+
+        Self { loops }
+    }
+
+    /// Returns the index of the innermost loop containing `block`.
+    pub fn innermost(&self, block: usize) -> Option<usize> {
+        (0..self.loops.len())
+            .filter(|&i| self.loops[i].body.contains(&block))
+            .max_by_key(|&i| self.loops[i].depth)
+    }
+
+    /// Returns how many loops contain `block`.
+    pub fn depth(&self, block: usize) -> usize {
+        self.innermost(block).map_or(0, |i| self.loops[i].depth)
+    }
+}
+
+impl Cfg {
+    /// Puts every natural loop into a canonical form for loop optimizations.
+    ///
+    /// Afterwards each loop has a preheader, the only block outside the loop which jumps to the
+    /// header, a single latch, and exit blocks which are only reached from inside the loop.
+    pub fn loop_simplify(&mut self) {
+        while self.insert_preheader().is_some() {}
+        while self.merge_latches() {}
+        while self.insert_dedicated_exit() {}
+    }
+
+    /// Gives the first loop without a preheader one, returning its label, or `None` if they all
+    /// have one.
+    pub(super) fn insert_preheader(&mut self) -> Option<String> {
+        let forest = LoopForest::from_cfg(self);
+        for l in &forest.loops {
+            let outside: Vec<usize> = self
+                .block(l.header)
+                .pred
+                .iter()
+                .copied()
+                .filter(|p| !l.body.contains(p))
+                .collect();
+            let has_preheader = l.header != 0
+                && outside.len() == 1
+                && self.block(outside[0]).flows_to == [l.header];
+            if has_preheader {
+                continue;
+            }
+
+            let label = self.label(l.header);
+            let label = self.fresh_label(&format!("{label}.preheader"));
+            let preheader = self.insert_block(l.header, label.clone(), l.header);
+            // Everything at or after the header moved down by one.
+            let header = l.header + 1;
+            for p in outside {
+                let p = if p >= l.header { p + 1 } else { p };
+                self.retarget(p, header, preheader);
+            }
+            return Some(label);
+        }
+        None
+    }
+
+    /// Funnels the back edges of the first loop with several latches through a new block,
+    /// returning false if every loop has a single latch.
+    fn merge_latches(&mut self) -> bool {
+        let forest = LoopForest::from_cfg(self);
+        let Some(l) = forest.loops.iter().find(|l| l.latches.len() > 1) else {
+            return false;
+        };
+        let label = self.label(l.header);
+        let label = self.fresh_label(&format!("{label}.latch"));
+        let latch = self.insert_block(l.header, label, l.header);
+        let header = l.header + 1;
+        for &p in &l.latches {
+            let p = if p >= l.header { p + 1 } else { p };
+            self.retarget(p, header, latch);
+        }
+        true
+    }
+
+    /// Splits the first exit edge into a block also reached from outside its loop, returning
+    /// false if every exit is dedicated.
+    fn insert_dedicated_exit(&mut self) -> bool {
+        let forest = LoopForest::from_cfg(self);
+        for l in &forest.loops {
+            for &exit in &l.exits {
+                let pred = &self.block(exit).pred;
+                if pred.iter().all(|p| l.body.contains(p)) {
+                    continue;
+                }
+                let inside: Vec<usize> = pred
+                    .iter()
+                    .copied()
+                    .filter(|p| l.body.contains(p))
+                    .collect();
+
+                let label = self.label(l.header);
+                let label = self.fresh_label(&format!("{label}.exit"));
+                let new_exit = self.insert_block(exit, label, exit);
+                for p in inside {
+                    let p = if p >= exit { p + 1 } else { p };
+                    self.retarget(p, exit + 1, new_exit);
+                }
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -2,7 +2,10 @@ use std::collections::HashMap;
 
 use crate::parser::ast::{ConstOps, EffectOp, Instruction, Literal, Type, ValueOp};
 
-use super::analysis::BasicBlock;
+use super::{
+    analysis::{BasicBlock, fold},
+    purity::Purity,
+};
 
 type ValueNum = usize;
 
@@ -29,6 +32,8 @@ enum AbstractValue {
     Opaque {
         var: String,
     },
+    /// The only variable holding this value has been overwritten.
+    Clobbered,
 }
 
 impl AbstractValue {
@@ -65,11 +70,111 @@ impl AbstractValue {
         }
     }
 
+    /// Returns true if the value turns out to be that of one of its operands.
     fn canonicalize(
         &mut self,
         _lvn: &HashMap<String, ValueNum>,
-        _info: &HashMap<ValueNum, ValueInfo>,
-    ) {
+        info: &HashMap<ValueNum, ValueInfo>,
+    ) -> bool {
+        let Self::Value { op, ty, args, .. } = self else {
+            return false;
+        };
+        if matches!(
+            op,
+            ValueOp::Add | ValueOp::Mul | ValueOp::Eq | ValueOp::And | ValueOp::Or
+        ) {
+            args.sort_unstable();
+        }
+
+        let literals: Option<Vec<Literal>> = args.iter().map(|a| Self::literal(info, *a)).collect();
+        if let Some(literals) = literals
+            && !matches!(op, ValueOp::Call)
+            && let Some(value) = fold(op, &literals)
+        {
+            *self = Self::Constant {
+                op: ConstOps::Const,
+                ty: *ty,
+                value,
+            };
+        } else if let Some(simpler) = Self::simplify(op, *ty, args, info) {
+            let is_operand = !matches!(simpler, Self::Constant { .. });
+            *self = simpler;
+            return is_operand;
+        }
+        false
+    }
+
+    fn literal(info: &HashMap<ValueNum, ValueInfo>, num: ValueNum) -> Option<Literal> {
+        match &info[&num].value {
+            Self::Constant { value, .. } => Some(value.clone()),
+            Self::Value { .. } | Self::Opaque { .. } | Self::Clobbered => None,
+        }
+    }
+
+    /// Applies algebraic identities, returning either a constant or the value of an operand.
+    fn simplify(
+        op: &ValueOp,
+        ty: Type,
+        args: &[ValueNum],
+        info: &HashMap<ValueNum, ValueInfo>,
+    ) -> Option<Self> {
+        use Literal::{Bool, Int};
+        let constant = |value| {
+            Some(Self::Constant {
+                op: ConstOps::Const,
+                ty,
+                value,
+            })
+        };
+        let operand = |num: ValueNum| match &info[&num].value {
+            Self::Clobbered => None,
+            value => Some(value.clone()),
+        };
+        let lit = |num: ValueNum| Self::literal(info, num);
+        match (op, args) {
+            (ValueOp::Id, [a]) => operand(*a),
+            (ValueOp::Sub, [a, b]) if a == b => constant(Int(0)),
+            (ValueOp::Sub, [a, b]) if lit(*b) == Some(Int(0)) => operand(*a),
+            (ValueOp::Add, [a, b]) | (ValueOp::Add, [b, a]) if lit(*b) == Some(Int(0)) => {
+                operand(*a)
+            }
+            (ValueOp::Mul, [a, b]) if lit(*a) == Some(Int(0)) || lit(*b) == Some(Int(0)) => {
+                constant(Int(0))
+            }
+            (ValueOp::Mul, [a, b]) | (ValueOp::Mul, [b, a]) if lit(*b) == Some(Int(1)) => {
+                operand(*a)
+            }
+            (ValueOp::Div, [a, b]) if lit(*b) == Some(Int(1)) => operand(*a),
+            (ValueOp::Eq | ValueOp::Le | ValueOp::Ge, [a, b]) if a == b => constant(Bool(true)),
+            (ValueOp::Lt | ValueOp::Gt, [a, b]) if a == b => constant(Bool(false)),
+            (ValueOp::And | ValueOp::Or, [a, b]) if a == b => operand(*a),
+            (ValueOp::And, [a, b])
+                if lit(*a) == Some(Bool(false)) || lit(*b) == Some(Bool(false)) =>
+            {
+                constant(Bool(false))
+            }
+            (ValueOp::And, [a, b]) | (ValueOp::And, [b, a]) if lit(*b) == Some(Bool(true)) => {
+                operand(*a)
+            }
+            (ValueOp::Or, [a, b]) if lit(*a) == Some(Bool(true)) || lit(*b) == Some(Bool(true)) => {
+                constant(Bool(true))
+            }
+            (ValueOp::Or, [a, b]) | (ValueOp::Or, [b, a]) if lit(*b) == Some(Bool(false)) => {
+                operand(*a)
+            }
+            (ValueOp::Not, [a]) => match &info[a].value {
+                Self::Value {
+                    op: ValueOp::Not,
+                    args,
+                    ..
+                } => operand(args[0]),
+                Self::Constant { .. }
+                | Self::Value { .. }
+                | Self::Opaque { .. }
+                | Self::Clobbered => None,
+            },
+            _ => None,
+        }
     }
 }
 
@@ -128,7 +233,7 @@ impl BasicBlock {
         }
     }
 
-    fn canonicalize_values(&mut self) {
+    fn canonicalize_values(&mut self, purity: &Purity) {
         let mut next_num = 0;
         let mut fresh_idx = 0;
         let mut lvn = HashMap::new();
@@ -148,7 +253,27 @@ impl BasicBlock {
                 i @ Instruction::Constant { dest, ty, .. }
                 | i @ Instruction::Value { dest, ty, .. } => {
                     let mut abstr = AbstractValue::from_instruction(i, &lvn, &last_dest).unwrap();
-                    let dest = match info.values().find(|v| {
+                    let previous = lvn.get(dest).copied();
+                    // Before `dest` is overwritten, let another variable holding the same value
+                    // stand in for it.
+                    let held: Vec<ValueNum> = info
+                        .iter()
+                        .filter(|(_, v)| v.src == *dest)
+                        .map(|(&num, _)| num)
+                        .collect();
+                    for num in held {
+                        let holder = lvn
+                            .iter()
+                            .filter(|&(var, &n)| n == num && var != dest)
+                            .map(|(var, _)| var.clone())
+                            .min();
+                        if let Some(holder) = holder {
+                            info.get_mut(&num).unwrap().src = holder;
+                        }
+                    }
+                    // A variable renamed earlier in the block must keep being renamed, since
+                    // later uses already refer to the fresh name.
+                    let overwrites_value = info.values().any(|v| {
                         v.src == *dest
                             && !matches!(
                                 v,
@@ -157,29 +282,67 @@ impl BasicBlock {
                                     ..
                                 }
                             )
-                    }) {
-                        Some(_) => {
-                            let fresh = format!("__brilro_fresh{fresh_idx}");
-                            fresh_idx += 1;
-                            last_dest.insert(dest.clone(), (fresh.clone(), *ty));
-                            match &mut new_insn {
-                                Instruction::Effect { .. } | Instruction::Label { .. } => {}
-                                Instruction::Constant { dest, .. }
-                                | Instruction::Value { dest, .. } => {
-                                    dest.clear();
-                                    dest.push_str(&fresh);
-                                }
+                    });
+                    let renamed = overwrites_value || last_dest.contains_key(dest);
+                    let dest = if renamed {
+                        let fresh = format!("__brilro_fresh{fresh_idx}");
+                        fresh_idx += 1;
+                        last_dest.insert(dest.clone(), (fresh.clone(), *ty));
+                        match &mut new_insn {
+                            Instruction::Effect { .. } | Instruction::Label { .. } => {}
+                            Instruction::Constant { dest, .. }
+                            | Instruction::Value { dest, .. } => {
+                                dest.clear();
+                                dest.push_str(&fresh);
+                            }
+                        }
+                        fresh
+                    } else {
+                        for v in info.values_mut() {
+                            if v.src == *dest {
+                                v.value = AbstractValue::Clobbered;
                             }
-                            fresh
                         }
-                        None => dest.clone(),
+                        dest.clone()
                     };
-                    abstr.canonicalize(&lvn, &info);
+                    let is_operand = abstr.canonicalize(&lvn, &info);
+                    if let AbstractValue::Constant { op, ty, value } = &abstr
+                        && let Instruction::Value { dest, span, .. } = &new_insn
+                    {
+                        new_insn = Instruction::Constant {
+                            op: op.clone(),
+                            dest: dest.clone(),
+                            ty: *ty,
+                            value: value.clone(),
+                            span: span.clone(),
+                        };
+                    }
+                    // Each call with side effects makes a value of its own.
+                    let shared = !purity.is_impure_call(insn);
+                    // Assigning a variable the value it already holds does nothing.
+                    if shared && !renamed && previous.is_some_and(|k| info[&k].value == abstr) {
+                        continue;
+                    }
                     let mut found = false;
                     for (&k, v) in &info {
-                        if v.value == abstr {
+                        if shared && v.value == abstr {
                             lvn.insert(dest.clone(), k);
                             found = true;
+                            // An identity leaves a copy of the operand, which dce can remove
+                            // once the later uses read the operand instead.
+                            if is_operand
+                                && let Instruction::Value { dest, ty, span, .. } = &new_insn
+                            {
+                                new_insn = Instruction::Value {
+                                    op: ValueOp::Id,
+                                    dest: dest.clone(),
+                                    ty: *ty,
+                                    args: vec![v.src.clone()],
+                                    funcs: vec![],
+                                    labels: vec![],
+                                    span: span.clone(),
+                                };
+                            }
                             break;
                         }
                     }
@@ -219,7 +382,8 @@ impl BasicBlock {
         self.instrs = new_instrs;
     }
 
-    pub fn lvn(&mut self) {
-        self.canonicalize_values()
+    /// Calls to functions `purity` says are pure are numbered like any other value.
+    pub fn lvn(&mut self, purity: &Purity) {
+        self.canonicalize_values(purity)
     }
 }
@@ -1,6 +1,21 @@
 pub mod analysis;
-pub mod dce;
-pub mod lvn;
+pub mod bit_vector;
+pub mod call_graph;
+pub mod chains;
+pub mod copy_prop;
 pub mod data_flow;
+pub mod dce;
 pub mod dominator;
+pub mod gcse;
+pub mod gvn;
+pub mod induction;
+pub mod inline;
+pub mod ipcp;
+pub mod licm;
+pub mod loops;
+pub mod lvn;
+pub mod purity;
+pub mod sccp;
 pub mod ssa;
+pub mod tail_call;
+pub mod unroll;
@@ -0,0 +1,109 @@
+use std::collections::BTreeSet;
+
+use crate::parser::ast::{EffectOp, Function, Instruction, Program, ValueOp};
+
+use super::{analysis::Cfg, call_graph::CallGraph};
+
+/// Which functions are pure: they print nothing, write no memory, only call pure functions, and
+/// always return since they have no loops and aren't recursive.
+///
+/// A call to a pure function can be removed if its result is unused, and two calls with the same
+/// arguments give the same value. Nothing is pure by default.
+#[derive(Debug, Clone, Default)]
+pub struct Purity {
+    pub pure: BTreeSet<String>,
+}
+
+/// Whether the CFG has a cycle reachable from the entry.
+fn has_cycle(cfg: &Cfg) -> bool {
+    fn visit(cfg: &Cfg, b: usize, on_path: &mut Vec<usize>, done: &mut BTreeSet<usize>) -> bool {
+        if on_path.contains(&b) {
+            return true;
+        }
+        if !done.insert(b) {
+            return false;
+        }
+        on_path.push(b);
+        let cyclic = cfg
+            .block(b)
+            .flows_to
+            .iter()
+            .any(|&s| visit(cfg, s, on_path, done));
+        on_path.pop();
+        cyclic
+    }
+    let Some(entry) = cfg.blocks.first() else {
+        return false;
+    };
+    visit(cfg, entry.start, &mut vec![], &mut BTreeSet::new())
+}
+
+impl Purity {
+    pub fn from_program(prog: &Program) -> Self {
+        let graph = CallGraph::from_program(prog);
+        let mut purity = Self::default();
+        // Callees come first, so each function only needs its callees' results.
+        for scc in graph.sccs() {
+            let [name] = &scc[..] else {
+                continue;
+            };
+            let f = prog.functions.iter().find(|f| &f.name == name).unwrap();
+            if !graph.is_recursive(name) && purity.body_is_pure(f) {
+                purity.pure.insert(name.clone());
+            }
+        }
+        purity
+    }
+
+    fn body_is_pure(&self, f: &Function) -> bool {
+        let effect_free = f.instrs.iter().all(|insn| match insn {
+            Instruction::Effect {
+                op: EffectOp::Print | EffectOp::Speculate | EffectOp::Commit | EffectOp::Guard,
+                ..
+            } => false,
+            Instruction::Value {
+                op: ValueOp::Call, ..
+            }
+            | Instruction::Effect {
+                op: EffectOp::Call, ..
+            } => self.is_pure_call(insn),
+            _ => true,
+        });
+        effect_free && !has_cycle(&Cfg::from_function(f))
+    }
+
+    pub fn is_pure(&self, f: &str) -> bool {
+        self.pure.contains(f)
+    }
+
+    /// Whether `insn` is a call to a pure function.
+    pub fn is_pure_call(&self, insn: &Instruction) -> bool {
+        match insn {
+            Instruction::Value {
+                op: ValueOp::Call,
+                funcs,
+                ..
+            }
+            | Instruction::Effect {
+                op: EffectOp::Call,
+                funcs,
+                ..
+            } => self.is_pure(&funcs[0]),
+            _ => false,
+        }
+    }
+
+    /// Whether `insn` is a call which may have side effects.
+    pub fn is_impure_call(&self, insn: &Instruction) -> bool {
+        matches!(
+            insn,
+            Instruction::Value {
+                op: ValueOp::Call,
+                ..
+            } | Instruction::Effect {
+                op: EffectOp::Call,
+                ..
+            }
+        ) && !self.is_pure_call(insn)
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::ast::{ConstOps, EffectOp, Instruction, Literal, ValueOp};
+
+use super::{
+    analysis::{BasicBlock, Cfg, Position, fold},
+    chains::Chains,
+};
+
+/// The lattice tracked for every SSA name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LatticeValue {
+    /// No executable definition has been seen yet.
+    Top,
+    Constant(Literal),
+    /// The value may differ between executions.
+    Bottom,
+}
+
+impl LatticeValue {
+    fn meet(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Self::Top, v) | (v, Self::Top) => v.clone(),
+            (Self::Constant(a), Self::Constant(b)) if a == b => self.clone(),
+            _ => Self::Bottom,
+        }
+    }
+}
+
+struct Sccp<'a> {
+    cfg: &'a Cfg,
+    values: HashMap<String, LatticeValue>,
+    executable: HashSet<usize>,
+    edges: HashSet<(usize, usize)>,
+    /// The start of the block with each label.
+    starts: HashMap<String, usize>,
+    chains: Chains,
+    /// Edges which have just become executable, for their target to be visited.
+    flow_worklist: Vec<(usize, usize)>,
+    /// Instructions reading a value which has just been lowered, or a `set` whose argument has.
+    ssa_worklist: Vec<Position>,
+}
+
+impl<'a> Sccp<'a> {
+    fn new(cfg: &'a Cfg) -> Self {
+        let values = cfg
+            .original_function
+            .args
+            .iter()
+            .map(|a| (a.name.clone(), LatticeValue::Bottom))
+            .collect();
+        let starts = cfg
+            .blocks
+            .iter()
+            .filter_map(|b| b.name.clone().map(|name| (name, b.start)))
+            .collect();
+        Self {
+            cfg,
+            values,
+            executable: HashSet::new(),
+            edges: HashSet::new(),
+            starts,
+            chains: Chains::from_ssa(cfg),
+            flow_worklist: vec![],
+            ssa_worklist: vec![],
+        }
+    }
+
+    fn value(&self, var: &str) -> LatticeValue {
+        self.values.get(var).cloned().unwrap_or(LatticeValue::Top)
+    }
+
+    /// Lowers `var` to its meet with `v`, returning true if anything changed.
+    fn lower(&mut self, var: &str, v: LatticeValue) -> bool {
+        let old = self.value(var);
+        let new = old.meet(&v);
+        if new != old {
+            self.values.insert(var.to_string(), new);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The argument of the last `set` to `shadow` in `block`, if there is one.
+    fn last_set<'b>(block: &'b BasicBlock, shadow: &str) -> Option<&'b String> {
+        block.instrs.iter().rev().find_map(|insn| match insn {
+            Instruction::Effect {
+                op: EffectOp::Set,
+                args,
+                ..
+            } if args[0] == shadow => Some(&args[1]),
+            _ => None,
+        })
+    }
+
+    /// The value a `get` of `shadow` at the head of `block_start` can see.
+    ///
+    /// When every executable incoming edge comes from a block ending with a `set` of `shadow`,
+    /// only those sets matter. Otherwise fall back to every `set` of `shadow` which may run.
+    fn evaluate_get(&self, block_start: usize, shadow: &str) -> LatticeValue {
+        let block = self.cfg.block(block_start);
+        let incoming = block
+            .pred
+            .iter()
+            .filter(|&&p| self.edges.contains(&(p, block_start)));
+        let last_sets: Option<Vec<&String>> = if block_start == 0 {
+            None
+        } else {
+            incoming
+                .map(|&p| Self::last_set(self.cfg.block(p), shadow))
+                .collect()
+        };
+        match last_sets {
+            Some(sets) => sets
+                .into_iter()
+                .fold(LatticeValue::Top, |acc, v| acc.meet(&self.value(v))),
+            None => self
+                .cfg
+                .blocks
+                .iter()
+                .filter(|b| self.executable.contains(&b.start))
+                .flat_map(|b| &b.instrs)
+                .filter_map(|insn| match insn {
+                    Instruction::Effect {
+                        op: EffectOp::Set,
+                        args,
+                        ..
+                    } if args[0] == shadow => Some(&args[1]),
+                    _ => None,
+                })
+                .fold(LatticeValue::Top, |acc, v| acc.meet(&self.value(v))),
+        }
+    }
+
+    /// The meet of the arguments of a `phi` in `block_start` along its executable incoming edges.
+    fn evaluate_phi(&self, block_start: usize, args: &[String], labels: &[String]) -> LatticeValue {
+        args.iter()
+            .zip(labels)
+            .filter(|(_, label)| self.edges.contains(&(self.starts[*label], block_start)))
+            .fold(LatticeValue::Top, |acc, (arg, _)| {
+                acc.meet(&self.value(arg))
+            })
+    }
+
+    fn evaluate(&self, block_start: usize, insn: &Instruction) -> LatticeValue {
+        match insn {
+            Instruction::Constant { value, .. } => LatticeValue::Constant(value.clone()),
+            Instruction::Value {
+                op: ValueOp::Get,
+                dest,
+                ..
+            } => self.evaluate_get(block_start, dest),
+            Instruction::Value {
+                op: ValueOp::Phi,
+                args,
+                labels,
+                ..
+            } => self.evaluate_phi(block_start, args, labels),
+            Instruction::Value {
+                op: ValueOp::Call | ValueOp::Undef,
+                ..
+            } => LatticeValue::Bottom,
+            Instruction::Value { op, args, .. } => {
+                let args: Vec<LatticeValue> = args.iter().map(|a| self.value(a)).collect();
+                let short_circuit = match op {
+                    ValueOp::And => Some(Literal::Bool(false)),
+                    ValueOp::Or => Some(Literal::Bool(true)),
+                    _ => None,
+                };
+                if let Some(lit) = short_circuit
+                    && args.contains(&LatticeValue::Constant(lit.clone()))
+                {
+                    return LatticeValue::Constant(lit);
+                }
+                if args.contains(&LatticeValue::Bottom) {
+                    return LatticeValue::Bottom;
+                }
+                let lits: Option<Vec<Literal>> = args
+                    .into_iter()
+                    .map(|a| match a {
+                        LatticeValue::Constant(lit) => Some(lit),
+                        LatticeValue::Top | LatticeValue::Bottom => None,
+                    })
+                    .collect();
+                match lits {
+                    Some(lits) => {
+                        fold(op, &lits).map_or(LatticeValue::Bottom, LatticeValue::Constant)
+                    }
+                    None => LatticeValue::Top,
+                }
+            }
+            Instruction::Effect { .. } | Instruction::Label { .. } => LatticeValue::Top,
+        }
+    }
+
+    /// The successors of `block` which can currently be shown to execute.
+    fn executable_successors(&self, block: &BasicBlock) -> Vec<usize> {
+        match block.instrs.last() {
+            Some(Instruction::Effect {
+                op: EffectOp::Br,
+                args,
+                ..
+            }) => match self.value(&args[0]) {
+                LatticeValue::Top => vec![],
+                LatticeValue::Constant(Literal::Bool(true)) => vec![block.flows_to[0]],
+                LatticeValue::Constant(Literal::Bool(false)) => vec![block.flows_to[1]],
+                LatticeValue::Constant(_) | LatticeValue::Bottom => block.flows_to.clone(),
+            },
+            _ => block.flows_to.clone(),
+        }
+    }
+
+    /// Evaluates the instruction at `pos`, queueing whatever depends on it if it changed.
+    fn visit(&mut self, (block_start, i): Position) {
+        let block = self.cfg.block(block_start);
+        let insn = &block.instrs[i];
+        match insn {
+            Instruction::Constant { dest, .. } | Instruction::Value { dest, .. } => {
+                let v = self.evaluate(block_start, insn);
+                if self.lower(dest, v) {
+                    self.ssa_worklist
+                        .extend(self.chains.users((block_start, i)));
+                }
+            }
+            // What a `set` passes along is only seen by the `get`s of its shadow variable.
+            Instruction::Effect {
+                op: EffectOp::Set, ..
+            } => self
+                .ssa_worklist
+                .extend(self.chains.users((block_start, i))),
+            Instruction::Effect { .. } | Instruction::Label { .. } => {}
+        }
+        if i + 1 == block.instrs.len() {
+            self.visit_successors(block);
+        }
+    }
+
+    fn visit_successors(&mut self, block: &BasicBlock) {
+        for succ in self.executable_successors(block) {
+            if self.edges.insert((block.start, succ)) {
+                self.flow_worklist.push((block.start, succ));
+            }
+        }
+    }
+
+    /// Finds the executable edges and the value of every name.
+    ///
+    /// Blocks are visited in full the first time an edge into them becomes executable, and only
+    /// their `phi`s and `get`s on later edges. After that an instruction is only evaluated again
+    /// when a value it reads is lowered.
+    fn solve(&mut self) {
+        let cfg = self.cfg;
+        let Some(entry) = cfg.blocks.first() else {
+            return;
+        };
+        self.executable.insert(entry.start);
+        self.visit_block(entry);
+        loop {
+            if let Some((_, to)) = self.flow_worklist.pop() {
+                let block = cfg.block(to);
+                if self.executable.insert(to) {
+                    self.visit_block(block);
+                } else {
+                    for (i, insn) in block.instrs.iter().enumerate() {
+                        if matches!(
+                            insn,
+                            Instruction::Value {
+                                op: ValueOp::Phi | ValueOp::Get,
+                                ..
+                            }
+                        ) {
+                            self.visit((to, i));
+                        }
+                    }
+                }
+            } else if let Some(pos) = self.ssa_worklist.pop() {
+                if self.executable.contains(&pos.0) {
+                    self.visit(pos);
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn visit_block(&mut self, block: &BasicBlock) {
+        for i in 0..block.instrs.len() {
+            self.visit((block.start, i));
+        }
+        if block.instrs.is_empty() {
+            self.visit_successors(block);
+        }
+    }
+
+    fn constant(&self, var: &str) -> Option<Literal> {
+        match self.value(var) {
+            LatticeValue::Constant(lit) => Some(lit),
+            LatticeValue::Top | LatticeValue::Bottom => None,
+        }
+    }
+
+    /// What the instruction `insn` in `block_start` becomes, if it changes.
+    fn folded(&self, block_start: usize, insn: &Instruction) -> Option<Instruction> {
+        match insn {
+            Instruction::Value {
+                op, dest, ty, span, ..
+            } if !matches!(op, ValueOp::Call) && self.constant(dest).is_some() => {
+                Some(Instruction::Constant {
+                    op: ConstOps::Const,
+                    dest: dest.clone(),
+                    ty: *ty,
+                    value: self.constant(dest).unwrap(),
+                    span: span.clone(),
+                })
+            }
+            // Edges which never run are gone, and so are their arguments.
+            Instruction::Value {
+                op: ValueOp::Phi,
+                dest,
+                ty,
+                args,
+                labels,
+                span,
+                ..
+            } => {
+                let (args, labels): (Vec<String>, Vec<String>) = args
+                    .iter()
+                    .zip(labels)
+                    .filter(|(_, label)| self.edges.contains(&(self.starts[*label], block_start)))
+                    .map(|(arg, label)| (arg.clone(), label.clone()))
+                    .unzip();
+                Some(Instruction::Value {
+                    op: ValueOp::Phi,
+                    dest: dest.clone(),
+                    ty: *ty,
+                    args,
+                    funcs: vec![],
+                    labels,
+                    span: span.clone(),
+                })
+            }
+            Instruction::Effect {
+                op: EffectOp::Br,
+                args,
+                labels,
+                span,
+                ..
+            } => match self.constant(&args[0]) {
+                Some(Literal::Bool(taken)) => Some(Instruction::Effect {
+                    op: EffectOp::Jmp,
+                    args: vec![],
+                    funcs: vec![],
+                    labels: vec![labels[usize::from(!taken)].clone()],
+                    span: span.clone(),
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn rewrite(&self) -> Cfg {
+        let mut cfg = self.cfg.clone();
+        cfg.blocks.retain(|b| self.executable.contains(&b.start));
+        let mut chains = Chains::from_ssa(&cfg);
+
+        let folded_gets: HashSet<String> = cfg
+            .blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .filter_map(|insn| match insn {
+                Instruction::Value {
+                    op: ValueOp::Get,
+                    dest,
+                    ..
+                } if self.constant(dest).is_some() => Some(dest.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let starts: Vec<usize> = cfg.blocks.iter().map(|b| b.start).collect();
+        for start in starts {
+            // Going backwards, deleting an instruction doesn't move those still to visit.
+            for i in (0..cfg.block(start).instrs.len()).rev() {
+                let insn = &cfg.block(start).instrs[i];
+                if let Instruction::Effect {
+                    op: EffectOp::Set,
+                    args,
+                    ..
+                } = insn
+                    && folded_gets.contains(&args[0])
+                {
+                    chains.delete(&mut cfg, (start, i));
+                } else if let Some(folded) = self.folded(start, insn) {
+                    if let Instruction::Effect {
+                        op: EffectOp::Jmp,
+                        labels,
+                        ..
+                    } = &folded
+                    {
+                        let block = cfg.block_mut(start);
+                        block.flows_to = vec![self.starts[&labels[0]]];
+                    }
+                    chains.replace(&mut cfg, (start, i), folded);
+                }
+            }
+        }
+        // Folded `get`s and `phi`s may come before ones which are left, and those have to stay at
+        // the head.
+        for block in &mut cfg.blocks {
+            block.instrs.sort_by_key(|insn| match insn {
+                Instruction::Label { .. } => 0,
+                Instruction::Value {
+                    op: ValueOp::Phi | ValueOp::Get,
+                    ..
+                } => 1,
+                _ => 2,
+            });
+        }
+        cfg.recompute_preds();
+        cfg
+    }
+}
+
+/// Sparse conditional constant propagation over a function in SSA form.
+///
+/// Constants are propagated through `get`/`set` pairs and `phi`s, branches on constants become
+/// jumps, and blocks which can never execute are removed. The result is still in SSA form.
+pub fn sccp(cfg: &Cfg) -> Cfg {
+    let mut sccp = Sccp::new(cfg);
+    sccp.solve();
+    sccp.rewrite()
+}
@@ -1,59 +1,202 @@
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    str::FromStr,
+};
 
 use crate::{
     cfg::lvn::is_terminator,
-    parser::ast::{Arg, EffectOp, Function, Instruction, Type, ValueOp},
+    parser::ast::{Arg, EffectOp, Function, Instruction, Span, Type, ValueOp},
+};
+
+use super::{
+    analysis::{Cfg, dest, id, uses},
+    dominator::DominatorTree,
 };
 
-use super::{analysis::Cfg, dominator::DominatorTree};
+/// The argument of a `phi` for a predecessor along which the variable is never defined.
+const UNDEFINED: &str = "__undefined";
+
+/// How `to_ssa` merges the values of a variable where control flow joins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsaStyle {
+    /// A `phi` at the head of the block, naming the value coming from each predecessor label.
+    Phi,
+    /// A `get` of a shadow variable at the head of the block, which every predecessor `set`s.
+    Shadow,
+}
+
+impl FromStr for SsaStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "phi" => Ok(SsaStyle::Phi),
+            "shadow" => Ok(SsaStyle::Shadow),
+            _ => Err("unrecognized ssa style".to_string()),
+        }
+    }
+}
+
+/// Which blocks `to_ssa` gives a merge of a variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhiPlacement {
+    /// Every block in the iterated dominance frontier of the variable's definitions.
+    Minimal,
+    /// As minimal, but only for variables some block reads before writing them.
+    SemiPruned,
+    /// As minimal, but only in blocks where the variable is live on entry.
+    Pruned,
+}
+
+impl FromStr for PhiPlacement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minimal" => Ok(PhiPlacement::Minimal),
+            "semi-pruned" => Ok(PhiPlacement::SemiPruned),
+            "pruned" => Ok(PhiPlacement::Pruned),
+            _ => Err("unrecognized phi placement".to_string()),
+        }
+    }
+}
+
+/// For each block, the variables it reads before writing them and the variables it writes.
+fn upward_exposed(cfg: &Cfg) -> HashMap<usize, (BTreeSet<String>, BTreeSet<String>)> {
+    cfg.blocks
+        .iter()
+        .map(|block| {
+            let mut used = BTreeSet::new();
+            let mut defined = BTreeSet::new();
+            for insn in &block.instrs {
+                for arg in uses(insn) {
+                    if !defined.contains(arg) {
+                        used.insert(arg.clone());
+                    }
+                }
+                if let Some(dest) = dest(insn) {
+                    defined.insert(dest.clone());
+                }
+            }
+            (block.start, (used, defined))
+        })
+        .collect()
+}
+
+/// The variables live on entry to each block.
+fn live_in(cfg: &Cfg) -> HashMap<usize, BTreeSet<String>> {
+    let exposed = upward_exposed(cfg);
+    let mut live: HashMap<usize, BTreeSet<String>> = exposed
+        .iter()
+        .map(|(&b, (used, _))| (b, used.clone()))
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in cfg.blocks.iter().rev() {
+            let (used, defined) = &exposed[&block.start];
+            let mut inset = used.clone();
+            for succ in &block.flows_to {
+                inset.extend(live[succ].iter().filter(|v| !defined.contains(*v)).cloned());
+            }
+            if inset != live[&block.start] {
+                live.insert(block.start, inset);
+                changed = true;
+            }
+        }
+    }
+    live
+}
 
 #[derive(Debug)]
 struct PhiNode {
     dest: String,
-    args: HashMap<usize, String>,
+    args: BTreeMap<usize, String>,
 }
 
 impl PhiNode {
     fn from_dest(dest: &str) -> Self {
         Self {
             dest: dest.to_string(),
-            args: HashMap::new(),
+            args: BTreeMap::new(),
         }
     }
 }
 
+/// The variable an SSA name made by `to_ssa` was renamed from.
+///
+/// SSA names are the source name, a `.`, and a number, so this takes the number off again.
+/// Other names, like the temporaries other passes make, are their own source.
+pub fn source_name(var: &str) -> &str {
+    match var.rsplit_once('.') {
+        Some((source, n)) if !source.is_empty() && n.parse::<usize>().is_ok() => source,
+        _ => var,
+    }
+}
+
+fn span_of(insn: &Instruction) -> Option<Span> {
+    match insn {
+        Instruction::Constant { span, .. }
+        | Instruction::Value { span, .. }
+        | Instruction::Effect { span, .. }
+        | Instruction::Label { span, .. } => span.clone(),
+    }
+}
+
+/// Every variable named in `cfg`, including its parameters.
+fn all_names(cfg: &Cfg) -> HashSet<String> {
+    let mut names: HashSet<String> = cfg
+        .original_function
+        .args
+        .iter()
+        .map(|a| a.name.clone())
+        .collect();
+    for insn in cfg.blocks.iter().flat_map(|b| &b.instrs) {
+        names.extend(dest(insn).cloned());
+        names.extend(uses(insn).iter().cloned());
+    }
+    names
+}
+
 #[derive(Debug)]
 struct NameMaker {
     pub stack: HashMap<String, Vec<String>>,
     pub name_nums: HashMap<String, usize>,
+    /// Names already in use, which new ones must not be.
+    taken: HashSet<String>,
 }
 
 impl NameMaker {
-    fn new() -> Self {
+    fn new(taken: HashSet<String>) -> Self {
         Self {
             stack: HashMap::new(),
             name_nums: HashMap::new(),
+            taken,
+        }
+    }
+
+    /// A new SSA name for `name`, skipping any which are already variables.
+    fn fresh(&mut self, name: &str) -> String {
+        let idx = self.name_nums.entry(name.to_string()).or_default();
+        loop {
+            let new_name = format!("{name}.{idx}");
+            *idx += 1;
+            if self.taken.insert(new_name.clone()) {
+                return new_name;
+            }
         }
     }
 
     fn name(&mut self, name: &str) -> String {
-        self.stack
-            .entry(name.to_string())
-            .or_insert_with(|| {
-                let new_idx = self.name_nums.entry(name.to_string()).or_default();
-                let name = format!("{name}{new_idx}");
-                *new_idx += 1;
-                vec![name]
-            })
-            .last()
-            .unwrap()
-            .clone()
+        if !self.stack.contains_key(name) {
+            let new_name = self.fresh(name);
+            self.stack.insert(name.to_string(), vec![new_name]);
+        }
+        self.stack[name].last().unwrap().clone()
     }
 
     fn push(&mut self, name: &str) {
-        let new_idx = self.name_nums.entry(name.to_string()).or_default();
-        let new_name = format!("{name}{new_idx}");
-        *new_idx += 1;
+        let new_name = self.fresh(name);
         self.stack
             .entry(name.to_string())
             .or_default()
@@ -64,17 +207,16 @@ impl NameMaker {
 #[derive(Debug)]
 struct Ssaifier<'a> {
     cfg: Cfg,
-    defs: HashMap<&'a str, BTreeSet<(usize, Type)>>,
+    defs: BTreeMap<&'a str, BTreeSet<(usize, Type)>>,
     doms: DominatorTree,
-    phis: HashMap<usize, HashMap<&'a str, PhiNode>>,
+    phis: BTreeMap<usize, BTreeMap<&'a str, PhiNode>>,
     types: HashMap<String, Type>,
     func: Function,
-    old_arg_name: HashMap<String, String>,
 }
 
 impl<'a> Ssaifier<'a> {
     fn from_cfg_and_func(cfg: &'a Cfg, func: &'a Function) -> Self {
-        let mut defs: HashMap<&str, BTreeSet<(usize, Type)>> = HashMap::new();
+        let mut defs: BTreeMap<&str, BTreeSet<(usize, Type)>> = BTreeMap::new();
         let mut vars_defined: HashMap<usize, BTreeSet<&'a str>> = HashMap::new();
         let mut types = HashMap::new();
         for block in &cfg.blocks {
@@ -106,30 +248,52 @@ impl<'a> Ssaifier<'a> {
             cfg: cfg.clone(),
             defs,
             doms: DominatorTree::from_cfg(cfg),
-            phis: HashMap::new(),
+            phis: BTreeMap::new(),
             types,
             func: func.clone(),
-            old_arg_name: HashMap::new(),
         }
     }
 
-    fn compute_phis(&mut self) {
+    fn compute_phis(&mut self, placement: PhiPlacement) {
+        let globals: BTreeSet<String> = upward_exposed(&self.cfg)
+            .into_values()
+            .flat_map(|(used, _)| used)
+            .collect();
+        let live = live_in(&self.cfg);
+        let wanted = |var: &str, block: usize| match placement {
+            PhiPlacement::Minimal => true,
+            PhiPlacement::SemiPruned => globals.contains(var),
+            PhiPlacement::Pruned => live[&block].contains(var),
+        };
         for (&var, defs) in &mut self.defs {
             let mut defs_with_maybe_mods = defs.clone();
             let mut new_defs = defs.clone();
             while let Some((def, ty)) = defs_with_maybe_mods.pop_last() {
                 for &block in &self.doms.frontier[&def] {
+                    if !wanted(var, block) {
+                        continue;
+                    }
                     if !self.phis.contains_key(&block) || !self.phis[&block].contains_key(var) {
                         self.phis
                             .entry(block)
                             .or_default()
                             .insert(var, PhiNode::from_dest(var));
                         let instrs = &mut self.cfg.block_mut(block).instrs;
-                        let idx = if matches!(instrs[0], Instruction::Label { .. }) {
-                            1
-                        } else {
-                            0
-                        };
+                        // After the label and the `get`s already placed, so they stay in order.
+                        let idx = instrs
+                            .iter()
+                            .position(|insn| {
+                                !matches!(
+                                    insn,
+                                    Instruction::Label { .. }
+                                        | Instruction::Value {
+                                            op: ValueOp::Get,
+                                            ..
+                                        }
+                                )
+                            })
+                            .unwrap_or(instrs.len());
+                        let span = instrs.first().and_then(span_of);
                         instrs.insert(
                             idx,
                             Instruction::Value {
@@ -139,7 +303,7 @@ impl<'a> Ssaifier<'a> {
                                 args: vec![],
                                 funcs: vec![],
                                 labels: vec![],
-                                span: None,
+                                span,
                             },
                         );
                         defs_with_maybe_mods.insert((block, ty));
@@ -154,7 +318,7 @@ impl<'a> Ssaifier<'a> {
     fn replace_names(
         types: &mut HashMap<String, Type>,
         insn: &mut Instruction,
-        phis: &mut HashMap<usize, HashMap<&'a str, PhiNode>>,
+        phis: &mut BTreeMap<usize, BTreeMap<&'a str, PhiNode>>,
         block_start: usize,
         names: &mut NameMaker,
     ) {
@@ -209,15 +373,12 @@ impl<'a> Ssaifier<'a> {
         let block = self.cfg.block_mut(block_start);
         let old_stack = names.stack.clone();
         if block_start == 0 {
-            // Replace dest
-            if block_start == 0 {
-                for arg in &mut self.func.args {
-                    names.push(&arg.name);
-                    let name = names.name(&arg.name);
-                    self.types.insert(name.clone(), arg.ty);
-                    self.old_arg_name.insert(name.clone(), arg.name.clone());
-                    arg.name = name;
-                }
+            // The arguments are defined on the way into the entry block.
+            for arg in &mut self.func.args {
+                names.push(&arg.name);
+                let name = names.name(&arg.name);
+                self.types.insert(name.clone(), arg.ty);
+                arg.name = name;
             }
         }
         for insn in &mut block.instrs {
@@ -231,7 +392,9 @@ impl<'a> Ssaifier<'a> {
                 }
             }
         }
-        for &domed in &self.doms.im_dom[&block_start].clone() {
+        let mut domed_blocks: Vec<usize> = self.doms.im_dom[&block_start].iter().copied().collect();
+        domed_blocks.sort_unstable();
+        for domed in domed_blocks {
             if domed != block_start && self.cfg.block(block_start).flows_to.contains(&domed) {
                 self.rename_block(domed, names, vis);
             }
@@ -243,40 +406,13 @@ impl<'a> Ssaifier<'a> {
     }
 
     fn rename(&mut self) {
-        let mut name_marker = NameMaker::new();
+        let mut name_marker = NameMaker::new(all_names(&self.cfg));
         let mut vis: HashSet<usize> = HashSet::new();
         for block in &self.cfg.blocks.clone() {
             if !vis.contains(&block.start) {
                 self.rename_block(block.start, &mut name_marker, &mut vis);
             }
         }
-        let entry_block = self.cfg.block_mut(0);
-        if let Some(phis) = self.phis.get_mut(&0) {
-            for (var, phi) in phis {
-                let name = if let Some(name) = self
-                    .func
-                    .args
-                    .iter()
-                    .find(|a| &self.old_arg_name[&a.name] == var)
-                {
-                    name.name.clone()
-                } else {
-                    name_marker.push(var);
-                    name_marker.name(var)
-                };
-                entry_block.instrs.insert(
-                    0,
-                    Instruction::Effect {
-                        op: EffectOp::Set,
-                        args: vec![phi.dest.clone(), name.clone()],
-                        funcs: vec![],
-                        labels: vec![],
-                        span: None,
-                    },
-                );
-                phi.args.insert(0, name);
-            }
-        }
     }
 
     fn add_sets(&mut self) {
@@ -293,6 +429,7 @@ impl<'a> Ssaifier<'a> {
                         .insert(phi.dest.clone(), self.types[&orig.to_string()]);
                     self.types
                         .insert(set_arg.clone(), self.types[&orig.to_string()]);
+                    let span = instrs.last().and_then(span_of);
                     instrs.insert(
                         idx,
                         Instruction::Effect {
@@ -300,7 +437,7 @@ impl<'a> Ssaifier<'a> {
                             args: vec![phi.dest.clone(), set_arg.clone()],
                             funcs: vec![],
                             labels: vec![],
-                            span: None,
+                            span,
                         },
                     );
                 }
@@ -308,81 +445,91 @@ impl<'a> Ssaifier<'a> {
         }
     }
 
-    fn add_undef_to_block(
-        &mut self,
-        mut cur_defs: HashSet<String>,
-        block_start: usize,
-        vis: &mut HashSet<usize>,
-    ) {
-        if vis.contains(&block_start) {
-            return;
-        }
-        vis.insert(block_start);
-        let block = self.cfg.block_mut(block_start);
-        let mut num_inserted = 0;
-        for (idx, insn) in block.instrs.clone().into_iter().enumerate() {
-            match insn {
-                Instruction::Label { .. } => {}
-                Instruction::Constant { dest, .. } => {
-                    cur_defs.insert(dest);
-                }
-                Instruction::Value { args, dest, .. } => {
-                    for arg in &args {
-                        if !cur_defs.contains(arg) {
-                            block.instrs.insert(
-                                idx + num_inserted,
-                                Instruction::Value {
-                                    op: ValueOp::Undef,
-                                    dest: arg.clone(),
-                                    ty: self.types[arg],
-                                    args: vec![],
-                                    funcs: vec![],
-                                    labels: vec![],
-                                    span: None,
-                                },
-                            );
-                            num_inserted += 1;
-                            cur_defs.insert(arg.clone());
-                        }
-                    }
-                    cur_defs.insert(dest);
+    /// Replaces the `get`s at the heads of blocks with `phi`s naming the predecessors.
+    fn add_phis(&mut self) {
+        let mut defined: HashSet<String> = self.func.args.iter().map(|a| a.name.clone()).collect();
+        for block in &self.cfg.blocks {
+            for insn in &block.instrs {
+                if let Instruction::Constant { dest, .. } | Instruction::Value { dest, .. } = insn {
+                    defined.insert(dest.clone());
                 }
-                Instruction::Effect { args, op, .. } => {
-                    for (i, arg) in args.iter().enumerate() {
-                        if i == 0 && matches!(op, EffectOp::Set) {
-                            continue;
-                        }
-                        if !cur_defs.contains(arg) {
-                            block.instrs.insert(
-                                idx + num_inserted,
-                                Instruction::Value {
-                                    op: ValueOp::Undef,
-                                    dest: arg.clone(),
-                                    ty: self.types[arg],
-                                    args: vec![],
-                                    funcs: vec![],
-                                    labels: vec![],
-                                    span: None,
-                                },
-                            );
-                            num_inserted += 1;
-                            cur_defs.insert(arg.clone());
-                        }
+            }
+        }
+        for (&block, phis) in &self.phis {
+            for (orig, phi) in phis {
+                let mut args = vec![];
+                let mut labels = vec![];
+                for (&pred, arg) in &phi.args {
+                    labels.push(self.cfg.label(pred));
+                    if defined.contains(arg) {
+                        args.push(arg.clone());
+                    } else {
+                        args.push(UNDEFINED.to_string());
                     }
                 }
+                let ty = self.types[&orig.to_string()];
+                self.types.insert(phi.dest.clone(), ty);
+                let get = self
+                    .cfg
+                    .block_mut(block)
+                    .instrs
+                    .iter_mut()
+                    .find(|insn| {
+                        matches!(insn, Instruction::Value { op: ValueOp::Get, dest, .. } if *dest == phi.dest)
+                    })
+                    .unwrap();
+                *get = Instruction::Value {
+                    op: ValueOp::Phi,
+                    dest: phi.dest.clone(),
+                    ty,
+                    args,
+                    funcs: vec![],
+                    labels,
+                    span: span_of(get),
+                };
             }
         }
-        for &domed in &block.flows_to.clone() {
-            self.add_undef_to_block(cur_defs.clone(), domed, vis);
-        }
     }
 
+    /// Defines each name which is used but never defined with an `undef` at the top of the entry
+    /// block, where it dominates every use.
     fn add_undefs(&mut self) {
-        self.add_undef_to_block(
-            self.func.args.iter().map(|a| a.name.clone()).collect(),
-            0,
-            &mut HashSet::new(),
-        );
+        if self.cfg.blocks.is_empty() {
+            return;
+        }
+        let mut defined: HashSet<String> = self.func.args.iter().map(|a| a.name.clone()).collect();
+        for insn in self.cfg.blocks.iter().flat_map(|b| &b.instrs) {
+            defined.extend(dest(insn).cloned());
+        }
+        let mut undefs = vec![];
+        for insn in self.cfg.blocks.iter().flat_map(|b| &b.instrs) {
+            // A `phi` already marks the arguments which are never defined.
+            if matches!(
+                insn,
+                Instruction::Value {
+                    op: ValueOp::Phi,
+                    ..
+                }
+            ) {
+                continue;
+            }
+            for arg in uses(insn) {
+                if defined.insert(arg.clone()) {
+                    undefs.push(Instruction::Value {
+                        op: ValueOp::Undef,
+                        dest: arg.clone(),
+                        ty: self.types[arg],
+                        args: vec![],
+                        funcs: vec![],
+                        labels: vec![],
+                        span: span_of(insn),
+                    });
+                }
+            }
+        }
+        let instrs = &mut self.cfg.block_mut(0).instrs;
+        let idx = usize::from(matches!(instrs.first(), Some(Instruction::Label { .. })));
+        instrs.splice(idx..idx, undefs);
     }
 
     fn cfg(self) -> (Cfg, Vec<Arg>) {
@@ -390,51 +537,573 @@ impl<'a> Ssaifier<'a> {
     }
 }
 
-pub fn to_ssa(cfg: &Cfg, func: &Function) -> (Cfg, Vec<Arg>) {
-    let mut ssaifier = Ssaifier::from_cfg_and_func(cfg, func);
-    ssaifier.compute_phis();
+/// `cfg` with an empty entry block of its own if the first block is a loop header.
+///
+/// The arguments are defined on the way into the entry block, and nothing can merge them with
+/// the values coming round a loop back to it, so the loop gets a header the arguments reach
+/// along an ordinary edge instead.
+fn with_entry(cfg: &Cfg) -> Cfg {
+    let mut cfg = cfg.clone();
+    if cfg.blocks.first().is_some_and(|b| !b.pred.is_empty()) {
+        let label = cfg.fresh_label("entry");
+        cfg.insert_block(0, label, 0);
+    }
+    cfg
+}
+
+pub fn to_ssa(
+    cfg: &Cfg,
+    func: &Function,
+    style: SsaStyle,
+    placement: PhiPlacement,
+) -> (Cfg, Vec<Arg>) {
+    let cfg = with_entry(cfg);
+    let mut ssaifier = Ssaifier::from_cfg_and_func(&cfg, func);
+    ssaifier.compute_phis(placement);
     ssaifier.rename();
-    ssaifier.add_sets();
+    match style {
+        SsaStyle::Phi => ssaifier.add_phis(),
+        SsaStyle::Shadow => ssaifier.add_sets(),
+    }
     ssaifier.add_undefs();
     ssaifier.cfg()
 }
 
+/// How many `get`s `to_ssa` puts at the heads of blocks with the given placement.
+pub fn count_gets(cfg: &Cfg, func: &Function, placement: PhiPlacement) -> usize {
+    let cfg = with_entry(cfg);
+    let mut ssaifier = Ssaifier::from_cfg_and_func(&cfg, func);
+    ssaifier.compute_phis(placement);
+    ssaifier.phis.values().map(BTreeMap::len).sum()
+}
+
+/// A copy into `dest` on an edge into a block where values merge. It is from `src`, or from
+/// nothing if the variable isn't defined along the edge.
+pub(super) struct EdgeCopy {
+    pub(super) dest: String,
+    pub(super) ty: Type,
+    pub(super) src: Option<String>,
+    /// Where the merge the copy comes from is.
+    pub(super) span: Option<Span>,
+}
+
+fn ends_in_branch(cfg: &Cfg, block: usize) -> bool {
+    matches!(
+        cfg.block(block).instrs.last(),
+        Some(Instruction::Effect {
+            op: EffectOp::Br,
+            ..
+        })
+    )
+}
+
+/// Removes every `phi`, `get` and `set`, returning the copies they stand for on each edge, keyed
+/// by predecessor and successor.
+///
+/// A `get` takes the value of the last `set` of its shadow variable in each predecessor, or of
+/// an earlier `set` in its own block, which becomes a plain `id`.
+fn edge_copies(cfg: &mut Cfg) -> BTreeMap<(usize, usize), Vec<EdgeCopy>> {
+    let starts: HashMap<String, usize> = cfg
+        .blocks
+        .iter()
+        .filter_map(|b| b.name.clone().map(|name| (name, b.start)))
+        .collect();
+    let last_sets: HashMap<usize, HashMap<String, (String, Option<Span>)>> = cfg
+        .blocks
+        .iter()
+        .map(|block| {
+            let sets = block
+                .instrs
+                .iter()
+                .filter_map(|insn| match insn {
+                    Instruction::Effect {
+                        op: EffectOp::Set,
+                        args,
+                        span,
+                        ..
+                    } => Some((args[0].clone(), (args[1].clone(), span.clone()))),
+                    _ => None,
+                })
+                .collect();
+            (block.start, sets)
+        })
+        .collect();
+
+    let mut copies: BTreeMap<(usize, usize), Vec<EdgeCopy>> = BTreeMap::new();
+    for block in &mut cfg.blocks {
+        let mut sets: HashMap<String, String> = HashMap::new();
+        let mut instrs = vec![];
+        for insn in block.instrs.drain(..) {
+            match insn {
+                Instruction::Value {
+                    op: ValueOp::Phi,
+                    dest,
+                    ty,
+                    args,
+                    labels,
+                    span,
+                    ..
+                } => {
+                    for (arg, label) in args.into_iter().zip(labels) {
+                        copies
+                            .entry((starts[&label], block.start))
+                            .or_default()
+                            .push(EdgeCopy {
+                                dest: dest.clone(),
+                                ty,
+                                src: (arg != UNDEFINED).then_some(arg),
+                                span: span.clone(),
+                            });
+                    }
+                }
+                Instruction::Value {
+                    op: ValueOp::Get,
+                    dest,
+                    ty,
+                    span,
+                    ..
+                } => {
+                    if let Some(src) = sets.get(&dest) {
+                        instrs.push(id(dest, ty, src.clone(), span));
+                        continue;
+                    }
+                    for pred in &block.pred {
+                        if let Some((src, span)) = last_sets[pred].get(&dest) {
+                            copies
+                                .entry((*pred, block.start))
+                                .or_default()
+                                .push(EdgeCopy {
+                                    dest: dest.clone(),
+                                    ty,
+                                    src: Some(src.clone()),
+                                    span: span.clone(),
+                                });
+                        }
+                    }
+                }
+                Instruction::Effect {
+                    op: EffectOp::Set,
+                    args,
+                    ..
+                } => {
+                    sets.insert(args[0].clone(), args[1].clone());
+                }
+                insn => instrs.push(insn),
+            }
+        }
+        block.instrs = instrs;
+    }
+    copies
+}
+
+/// Puts a new block on each edge whose copies can go neither at the end of the predecessor,
+/// since it branches, nor at the start of the successor, since it has other predecessors.
+/// Returns the starts of the new blocks.
+fn split_edges(cfg: &mut Cfg, copies: &mut BTreeMap<(usize, usize), Vec<EdgeCopy>>) -> Vec<usize> {
+    let mut split = vec![];
+    while let Some(&(pred, succ)) = copies.keys().find(|&&(pred, succ)| {
+        ends_in_branch(cfg, pred) && (succ == 0 || cfg.block(succ).pred.len() > 1)
+    }) {
+        let label = cfg.label(succ);
+        let label = cfg.fresh_label(&format!("{label}.split"));
+        // Nothing can go before the entry block, so a block splitting an edge into it goes after.
+        let before = if succ == 0 { cfg.blocks[1].start } else { succ };
+        let new = cfg.insert_block(before, label, succ);
+        let shift = |s: usize| if s >= before { s + 1 } else { s };
+        *copies = std::mem::take(copies)
+            .into_iter()
+            .map(|((p, s), edge)| ((shift(p), shift(s)), edge))
+            .collect();
+        let (pred, succ) = (shift(pred), shift(succ));
+        cfg.retarget(pred, succ, new);
+        let edge = copies.remove(&(pred, succ)).unwrap();
+        copies.insert((new, succ), edge);
+        split = split.into_iter().map(shift).collect();
+        split.push(new);
+    }
+    split
+}
+
+/// Orders the copies on an edge, which happen all at once, so each value is read before it's
+/// overwritten. Copies reading each other's destinations in a cycle, like a swap, get one of
+/// their values saved in a temporary first.
+pub(super) fn sequentialize(cfg: &Cfg, edge: Vec<EdgeCopy>) -> Vec<Instruction> {
+    let (mut pending, undefined): (Vec<EdgeCopy>, Vec<EdgeCopy>) =
+        edge.into_iter().partition(|copy| copy.src.is_some());
+    pending.retain(|copy| copy.src.as_ref() != Some(&copy.dest));
+    let mut instrs = vec![];
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|copy| {
+            !pending
+                .iter()
+                .any(|other| other.src.as_ref() == Some(&copy.dest))
+        });
+        if let Some(i) = ready {
+            let copy = pending.remove(i);
+            instrs.push(id(copy.dest, copy.ty, copy.src.unwrap(), copy.span));
+        } else {
+            let EdgeCopy { dest, ty, span, .. } = &pending[0];
+            let temp = cfg.fresh_var(&format!("{}.swap", source_name(dest)));
+            instrs.push(id(temp.clone(), *ty, dest.clone(), span.clone()));
+            let dest = dest.clone();
+            for copy in &mut pending {
+                if copy.src.as_ref() == Some(&dest) {
+                    copy.src = Some(temp.clone());
+                }
+            }
+        }
+    }
+    // Nothing else on the edge reads these, since they're undefined along it.
+    for copy in undefined {
+        instrs.push(Instruction::Value {
+            op: ValueOp::Undef,
+            dest: copy.dest,
+            ty: copy.ty,
+            args: vec![],
+            funcs: vec![],
+            labels: vec![],
+            span: copy.span,
+        });
+    }
+    instrs
+}
+
+/// The pairs of variables which are live at the same time, in both orders. A copy's
+/// destination doesn't interfere with its source, since they hold the same value.
+fn interference(cfg: &Cfg) -> HashSet<(String, String)> {
+    let live = live_in(cfg);
+    let mut pairs = HashSet::new();
+    let mut add = |a: &String, b: &String| {
+        if a != b {
+            pairs.insert((a.clone(), b.clone()));
+            pairs.insert((b.clone(), a.clone()));
+        }
+    };
+    let params = &cfg.original_function.args;
+    for param in params {
+        for other in params.iter().map(|a| &a.name).chain(&live[&0]) {
+            add(&param.name, other);
+        }
+    }
+    for block in &cfg.blocks {
+        let mut after: BTreeSet<String> = block
+            .flows_to
+            .iter()
+            .flat_map(|s| live[s].iter().cloned())
+            .collect();
+        for insn in block.instrs.iter().rev() {
+            if let Some(dest) = dest(insn) {
+                let copied = match insn {
+                    Instruction::Value {
+                        op: ValueOp::Id,
+                        args,
+                        ..
+                    } => Some(&args[0]),
+                    _ => None,
+                };
+                for var in after.iter().filter(|&v| Some(v) != copied) {
+                    add(dest, var);
+                }
+                after.remove(dest);
+            }
+            after.extend(uses(insn).iter().cloned());
+        }
+    }
+    pairs
+}
+
+/// Gives both sides of each copy in `copies` one name when they never interfere, and removes
+/// the copies left copying a variable to itself.
+///
+/// Each group of names then goes back to the name of its source variable, unless it interferes
+/// with a group which already has it.
+fn coalesce(cfg: &mut Cfg, copies: &[(String, String)]) {
+    let interference = interference(cfg);
+    let interferes = |a: &[String], b: &[String]| {
+        a.iter().any(|x| {
+            b.iter()
+                .any(|y| interference.contains(&(x.clone(), y.clone())))
+        })
+    };
+    // The groups of names coalesced together, keyed by one of their members.
+    let mut classes: BTreeMap<String, Vec<String>> = all_names(cfg)
+        .into_iter()
+        .map(|v| (v.clone(), vec![v]))
+        .collect();
+    let mut class_of: HashMap<String, String> =
+        classes.keys().map(|v| (v.clone(), v.clone())).collect();
+    for (dest, src) in copies {
+        let (a, b) = (class_of[dest].clone(), class_of[src].clone());
+        if a == b || interferes(&classes[&a], &classes[&b]) {
+            continue;
+        }
+        let merged = classes.remove(&b).unwrap();
+        for v in &merged {
+            class_of.insert(v.clone(), a.clone());
+        }
+        classes.get_mut(&a).unwrap().extend(merged);
+    }
+
+    // Groups already named after their source, like parameters, go first so they keep it.
+    let mut order: Vec<(&String, &Vec<String>)> = classes.iter().collect();
+    order.sort_by_key(|(key, _)| source_name(key) != key.as_str());
+    let mut named: HashMap<String, Vec<String>> = HashMap::new();
+    let mut names: HashMap<String, String> = HashMap::new();
+    for (key, class) in order {
+        let source = source_name(key).to_string();
+        let name = if named
+            .get(&source)
+            .is_none_or(|others| !interferes(others, class))
+        {
+            source
+        } else {
+            key.clone()
+        };
+        named.entry(name.clone()).or_default().extend(class.clone());
+        for v in class {
+            names.insert(v.clone(), name.clone());
+        }
+    }
+
+    let rename = |v: &mut String| {
+        if let Some(name) = names.get(v) {
+            *v = name.clone();
+        }
+    };
+    for arg in &mut cfg.original_function.args {
+        rename(&mut arg.name);
+    }
+    for block in &mut cfg.blocks {
+        for insn in &mut block.instrs {
+            match insn {
+                Instruction::Constant { dest, .. } => rename(dest),
+                Instruction::Value { dest, args, .. } => {
+                    rename(dest);
+                    args.iter_mut().for_each(rename);
+                }
+                Instruction::Effect { args, .. } => args.iter_mut().for_each(rename),
+                Instruction::Label { .. } => {}
+            }
+        }
+        block.instrs.retain(|insn| {
+            !matches!(insn, Instruction::Value { op: ValueOp::Id, dest, args, .. } if args[0] == *dest)
+        });
+    }
+}
+
+/// Leaves SSA form, in either style `to_ssa` produces.
+///
+/// The merges at the head of each block become copies on its incoming edges, put at the end of
+/// the predecessor, or at the start of the block if the predecessor branches elsewhere too, or
+/// else in a new block on the edge. The copies on an edge are ordered so none overwrites a
+/// value another still needs, then the ones between names never live at once are coalesced
+/// away.
 pub fn from_ssa(cfg: &Cfg) -> Cfg {
     let mut new_cfg = cfg.clone();
-    let mut types: HashMap<String, Type> = HashMap::new();
-    for block in &new_cfg.blocks {
-        for insn in &block.instrs {
-            if let Instruction::Value { dest, ty, .. } = insn {
-                types.insert(dest.clone(), *ty);
+    let mut copies = edge_copies(&mut new_cfg);
+    let split = split_edges(&mut new_cfg, &mut copies);
+    let mut coalescable = vec![];
+    for ((pred, succ), edge) in copies {
+        let copies = sequentialize(&new_cfg, edge);
+        coalescable.extend(copies.iter().filter_map(|insn| match insn {
+            Instruction::Value {
+                op: ValueOp::Id,
+                dest,
+                args,
+                ..
+            } => Some((dest.clone(), args[0].clone())),
+            _ => None,
+        }));
+        if ends_in_branch(&new_cfg, pred) {
+            let instrs = &mut new_cfg.block_mut(succ).instrs;
+            let idx = usize::from(matches!(instrs.first(), Some(Instruction::Label { .. })));
+            instrs.splice(idx..idx, copies);
+        } else {
+            let instrs = &mut new_cfg.block_mut(pred).instrs;
+            let idx = if instrs.last().is_some_and(is_terminator) {
+                instrs.len() - 1
+            } else {
+                instrs.len()
+            };
+            instrs.splice(idx..idx, copies);
+        }
+    }
+    coalesce(&mut new_cfg, &coalescable);
+    // Blocks splitting edges whose copies were all coalesced away are left only jumping on.
+    for start in split {
+        let block = new_cfg.block(start);
+        if let [
+            Instruction::Label { .. },
+            Instruction::Effect {
+                op: EffectOp::Jmp, ..
+            },
+        ] = &block.instrs[..]
+        {
+            let target = block.flows_to[0];
+            for pred in block.pred.clone() {
+                new_cfg.retarget(pred, start, target);
             }
+            new_cfg.blocks.retain(|b| b.start != start);
+            new_cfg.recompute_preds();
         }
     }
-    for block in &mut new_cfg.blocks {
-        let mut new_insns = vec![];
-        for instr in &block.instrs {
-            match instr {
+    new_cfg
+}
+
+/// Names the block starting at `start` for messages.
+fn describe(cfg: &Cfg, start: usize) -> String {
+    match &cfg.block(start).name {
+        Some(name) => format!(".{name}"),
+        None if start == 0 => "the entry block".to_string(),
+        None => format!("the unlabelled block at {start}"),
+    }
+}
+
+/// Checks that `cfg` is in SSA form, in either style `to_ssa` produces, and describes every way
+/// it isn't.
+///
+/// Each variable must be defined once, by a parameter or an instruction, and every use must be
+/// dominated by that definition. A `get` or `phi` must come before anything else in its block
+/// but the label, a `get` needs a `set` of its shadow variable in every predecessor, and a `phi`
+/// needs an argument for every predecessor. Unreachable blocks aren't checked.
+pub fn verify(cfg: &Cfg) -> Vec<String> {
+    let mut errors = vec![];
+    let reachable = cfg.reachable();
+    let doms = DominatorTree::from_cfg(cfg);
+    let starts: HashMap<&str, usize> = cfg
+        .blocks
+        .iter()
+        .filter_map(|b| b.name.as_deref().map(|name| (name, b.start)))
+        .collect();
+
+    // Where each variable is defined, as a block and an index into it. Parameters have no index.
+    let mut defs: BTreeMap<&str, Vec<(usize, Option<usize>)>> = BTreeMap::new();
+    for arg in &cfg.original_function.args {
+        defs.entry(&arg.name).or_default().push((0, None));
+    }
+    for block in cfg.blocks.iter().filter(|b| reachable.contains(&b.start)) {
+        for (idx, insn) in block.instrs.iter().enumerate() {
+            if let Some(dest) = dest(insn) {
+                defs.entry(dest).or_default().push((block.start, Some(idx)));
+            }
+        }
+    }
+    for (var, sites) in &defs {
+        if sites.len() > 1 {
+            let places: Vec<String> = sites
+                .iter()
+                .map(|&(block, idx)| match idx {
+                    None => "the parameters".to_string(),
+                    Some(_) => describe(cfg, block),
+                })
+                .collect();
+            errors.push(format!(
+                "`{var}` is defined {} times, in {}",
+                sites.len(),
+                places.join(", ")
+            ));
+        }
+    }
+
+    // Describes what's wrong with using `var` just before `idx` in `block`, if anything.
+    let check_use = |var: &str, block: usize, idx: usize| {
+        let Some(sites) = defs.get(var) else {
+            return Some(format!(
+                "`{var}` is used in {} but never defined",
+                describe(cfg, block)
+            ));
+        };
+        let dominated = sites.iter().any(|&(def, def_idx)| {
+            if def == block {
+                def_idx.is_none_or(|i| i < idx)
+            } else {
+                doms.dom[&def].contains(&block)
+            }
+        });
+        (!dominated).then(|| {
+            format!(
+                "`{var}` is used in {} where its definition doesn't dominate it",
+                describe(cfg, block)
+            )
+        })
+    };
+
+    for block in cfg.blocks.iter().filter(|b| reachable.contains(&b.start)) {
+        let here = describe(cfg, block.start);
+        let preds: Vec<usize> = block
+            .pred
+            .iter()
+            .copied()
+            .filter(|p| reachable.contains(p))
+            .collect();
+        let mut at_head = true;
+        for (idx, insn) in block.instrs.iter().enumerate() {
+            match insn {
+                Instruction::Label { .. } => {}
                 Instruction::Value {
-                    op: ValueOp::Get, ..
-                } => {}
-                Instruction::Effect {
-                    op: EffectOp::Set,
+                    op: ValueOp::Get,
+                    dest,
+                    ..
+                } => {
+                    if !at_head {
+                        errors.push(format!("the `get` of `{dest}` in {here} isn't at its head"));
+                    }
+                    if preds.is_empty() {
+                        errors.push(format!(
+                            "the `get` of `{dest}` in {here} has no predecessor to `set` it"
+                        ));
+                    }
+                    for &pred in &preds {
+                        let sets = cfg.block(pred).instrs.iter().any(|insn| {
+                            matches!(
+                                insn,
+                                Instruction::Effect { op: EffectOp::Set, args, .. } if args[0] == *dest
+                            )
+                        });
+                        if !sets {
+                            errors.push(format!(
+                                "the `get` of `{dest}` in {here} has no `set` in {}",
+                                describe(cfg, pred)
+                            ));
+                        }
+                    }
+                }
+                Instruction::Value {
+                    op: ValueOp::Phi,
+                    dest,
                     args,
+                    labels,
                     ..
                 } => {
-                    new_insns.push(Instruction::Value {
-                        op: ValueOp::Id,
-                        dest: args[0].clone(),
-                        ty: types[&args[0]],
-                        args: vec![args[1].clone()],
-                        funcs: vec![],
-                        labels: vec![],
-                        span: None,
-                    });
+                    if !at_head {
+                        errors.push(format!("the `phi` of `{dest}` in {here} isn't at its head"));
+                    }
+                    for &pred in &preds {
+                        if !labels.iter().any(|l| starts.get(l.as_str()) == Some(&pred)) {
+                            errors.push(format!(
+                                "the `phi` of `{dest}` in {here} has no argument for {}",
+                                describe(cfg, pred)
+                            ));
+                        }
+                    }
+                    for (arg, label) in args.iter().zip(labels) {
+                        // The argument is used at the end of the predecessor it comes from.
+                        if let Some(&pred) = starts.get(label.as_str())
+                            && arg != UNDEFINED
+                        {
+                            let end = cfg.block(pred).instrs.len();
+                            errors.extend(check_use(arg, pred, end));
+                        }
+                    }
+                }
+                _ => {
+                    at_head = false;
+                    for arg in uses(insn) {
+                        errors.extend(check_use(arg, block.start, idx));
+                    }
                 }
-                i => new_insns.push(i.clone()),
             }
         }
-        block.instrs = new_insns;
     }
-    new_cfg
+    errors
 }
@@ -0,0 +1,92 @@
+use crate::parser::ast::{EffectOp, Instruction, ValueOp};
+
+use super::{
+    analysis::{Cfg, jump},
+    ssa::{EdgeCopy, sequentialize},
+};
+
+impl Cfg {
+    /// The arguments of the self-recursive call which ends `block` just before returning its
+    /// result, if there is one.
+    fn tail_call(&self, block: usize) -> Option<Vec<String>> {
+        let name = &self.original_function.name;
+        match &self.block(block).instrs[..] {
+            [
+                ..,
+                Instruction::Value {
+                    op: ValueOp::Call,
+                    dest,
+                    args,
+                    funcs,
+                    ..
+                },
+                Instruction::Effect {
+                    op: EffectOp::Ret,
+                    args: ret,
+                    ..
+                },
+            ] if funcs[0] == *name && ret[..] == [dest.clone()] => Some(args.clone()),
+            [
+                ..,
+                Instruction::Effect {
+                    op: EffectOp::Call,
+                    args,
+                    funcs,
+                    ..
+                },
+                Instruction::Effect {
+                    op: EffectOp::Ret,
+                    args: ret,
+                    ..
+                },
+            ] if funcs[0] == *name && ret.is_empty() => Some(args.clone()),
+            _ => None,
+        }
+    }
+
+    /// Tail-call elimination for self-recursive functions.
+    ///
+    /// A call of the function to itself whose result is returned straight away becomes an
+    /// assignment of the arguments to the parameters and a jump back to the old entry block,
+    /// which gets a new empty entry block in front of it so it can be a loop header.
+    pub fn tail_call_elimination(&mut self) {
+        let tail_calls: Vec<usize> = self
+            .blocks
+            .iter()
+            .map(|b| b.start)
+            .filter(|&b| self.tail_call(b).is_some())
+            .collect();
+        if tail_calls.is_empty() {
+            return;
+        }
+
+        let entry = self.fresh_label(&format!("{}.entry", self.original_function.name));
+        self.insert_block(0, entry, 0);
+        // Every other block moved down by one to make room.
+        let header = self.blocks[1].start;
+        let header_label = self.label(header);
+        let params = self.original_function.args.clone();
+        for b in tail_calls.into_iter().map(|b| b + 1) {
+            let args = self.tail_call(b).unwrap();
+            // The arguments are all passed at once, like the copies on an edge out of SSA.
+            let copies = params
+                .iter()
+                .zip(args)
+                .map(|(param, arg)| EdgeCopy {
+                    dest: param.name.clone(),
+                    ty: param.ty,
+                    src: Some(arg),
+                    span: None,
+                })
+                .collect();
+            let mut instrs = sequentialize(self, copies);
+            instrs.push(jump(header_label.clone()));
+
+            let block = self.block_mut(b);
+            block.instrs.truncate(block.instrs.len() - 2);
+            block.instrs.extend(instrs);
+            block.flows_to = vec![header];
+        }
+        self.recompute_preds();
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::parser::ast::{EffectOp, Instruction, Literal};
+
+use super::{
+    analysis::{BasicBlock, Cfg, dest, fold, jump, mentions},
+    data_flow::{ReachingDefinitions, ShimmedCfg},
+    dominator::DominatorTree,
+    induction::Induction,
+    loops::{LoopForest, NaturalLoop},
+    lvn::is_terminator,
+};
+
+/// Loops known to run at most this many times are unrolled completely.
+const FULL_UNROLL_LIMIT: usize = 8;
+
+/// Unrolling adds at most this many instructions to an outermost loop and the loops inside it.
+const NEST_SIZE_LIMIT: usize = 64;
+
+/// How the copies of a loop are being made.
+struct Unrolling<'a> {
+    l: &'a NaturalLoop,
+    /// The label of each loop block in each copy. The first copy is the original loop.
+    labels: Vec<HashMap<usize, String>>,
+    /// The new names of block local variables in each copy.
+    renames: Vec<HashMap<String, String>>,
+    block_of: HashMap<String, usize>,
+    names: HashMap<usize, String>,
+    /// Whether the loop is being unrolled completely, with the last copy only exiting.
+    full: bool,
+}
+
+impl Unrolling<'_> {
+    fn copies(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Where copy `m` of the edge to `target` goes.
+    fn target(&self, m: usize, target: usize) -> String {
+        if target == self.l.header {
+            // Back edges out of the last copy of a fully unrolled loop are unreachable.
+            let next = if self.full {
+                (m + 1).min(self.copies() - 1)
+            } else {
+                (m + 1) % self.copies()
+            };
+            self.labels[next][&target].clone()
+        } else if self.l.body.contains(&target) {
+            self.labels[m][&target].clone()
+        } else {
+            self.names[&target].clone()
+        }
+    }
+
+    /// Copy `m` of a block in the loop.
+    fn copy(&self, m: usize, block: &BasicBlock) -> Vec<Instruction> {
+        let mut out = vec![];
+        for insn in &block.instrs {
+            let mut insn = insn.clone();
+            for var in mentions(&mut insn) {
+                if let Some(new) = self.renames[m].get(var) {
+                    *var = new.clone();
+                }
+            }
+            match &mut insn {
+                Instruction::Label { label, .. } => *label = self.labels[m][&block.start].clone(),
+                Instruction::Effect {
+                    op: EffectOp::Br,
+                    labels,
+                    ..
+                } if self.full && block.start == self.l.header => {
+                    // The trip count says which way every copy of the exit check goes.
+                    let last = m + 1 == self.copies();
+                    let target = labels
+                        .iter()
+                        .map(|l| self.block_of[l])
+                        .find(|t| self.l.body.contains(t) != last)
+                        .unwrap();
+                    insn = jump(self.target(m, target));
+                }
+                Instruction::Effect {
+                    op: EffectOp::Jmp | EffectOp::Br,
+                    labels,
+                    ..
+                } => {
+                    for label in labels.iter_mut() {
+                        *label = self.target(m, self.block_of[label]);
+                    }
+                }
+                _ => {}
+            }
+            out.push(insn);
+        }
+        if !block.instrs.last().is_some_and(is_terminator)
+            && let [succ] = block.flows_to[..]
+            && (m > 0 || succ == self.l.header)
+        {
+            out.push(jump(self.target(m, succ)));
+        }
+        out
+    }
+}
+
+impl Cfg {
+    /// Loop unrolling of innermost loops.
+    ///
+    /// A loop whose trip count is a constant of at most `FULL_UNROLL_LIMIT` is replaced by that
+    /// many copies of its body. Other loops get `factor` copies chained by their back edges, each
+    /// keeping its own exit check. Copies get fresh labels, and variables which never leave a
+    /// single block get fresh names in each copy. They are laid out next to the loop, so a back
+    /// edge which fell through or jumped to the header falls into the next copy.
+    ///
+    /// The copies made inside each outermost loop add up to at most `NEST_SIZE_LIMIT`
+    /// instructions, and loops which would go over are unrolled less or not at all.
+    pub fn unroll(&mut self, factor: usize) {
+        let forest = LoopForest::from_cfg(self);
+        let innermost: Vec<(usize, usize)> = forest
+            .loops
+            .iter()
+            .filter(|l| l.children.is_empty())
+            .map(|l| {
+                let mut nest = l;
+                while let Some(parent) = nest.parent {
+                    nest = &forest.loops[parent];
+                }
+                (l.header, nest.header)
+            })
+            .collect();
+        let headers: Vec<(String, String)> = innermost
+            .into_iter()
+            .map(|(h, nest)| (self.label(h), self.label(nest)))
+            .collect();
+        let mut budgets: HashMap<String, usize> = HashMap::new();
+        for (header, nest) in headers {
+            let forest = LoopForest::from_cfg(self);
+            let l = forest
+                .loops
+                .iter()
+                .find(|l| self.block(l.header).name.as_ref() == Some(&header))
+                .unwrap();
+            let size = l
+                .body
+                .iter()
+                .flat_map(|&b| &self.block(b).instrs)
+                .filter(|insn| !matches!(insn, Instruction::Label { .. }))
+                .count();
+            let budget = budgets.entry(nest).or_insert(NEST_SIZE_LIMIT);
+            match self.trip_count(l) {
+                Some(trips) if trips <= FULL_UNROLL_LIMIT && size * trips <= *budget => {
+                    *budget -= size * trips;
+                    self.unroll_loop(l, trips + 1, true);
+                }
+                _ if factor > 1 => {
+                    let copies = (*budget / size.max(1) + 1).min(factor);
+                    if copies > 1 {
+                        *budget -= size * (copies - 1);
+                        self.unroll_loop(l, copies, false);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The value of `var` leaving `block` if it's always the same constant, which needs every path
+    /// to `block` to define it.
+    fn constant_out(
+        &self,
+        solved: &ShimmedCfg<ReachingDefinitions>,
+        block: usize,
+        var: &str,
+    ) -> Option<Literal> {
+        let mut defs = solved.outset(block).iter().filter(|(_, v)| v == var);
+        let (Some(((def_block, def_idx), _)), None) = (defs.next(), defs.next()) else {
+            return None;
+        };
+        match &self.block(*def_block).instrs[*def_idx] {
+            Instruction::Constant { value, .. } => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// The number of times the body of `l` runs, if the header exits by comparing a basic
+    /// induction variable with constants, and every value involved is known on entry.
+    fn trip_count(&self, l: &NaturalLoop) -> Option<usize> {
+        let header = self.block(l.header);
+        let Some(Instruction::Effect {
+            op: EffectOp::Br,
+            args: cond,
+            ..
+        }) = header.instrs.last()
+        else {
+            return None;
+        };
+        let stay_when = match header.flows_to[..] {
+            [t, f] if l.body.contains(&t) && !l.body.contains(&f) => true,
+            [t, f] if !l.body.contains(&t) && l.body.contains(&f) => false,
+            _ => return None,
+        };
+        let Some(Instruction::Value { op, args, .. }) = header
+            .instrs
+            .iter()
+            .rev()
+            .find(|insn| dest(insn) == Some(&cond[0]))
+        else {
+            return None;
+        };
+
+        // The induction variable has to move exactly once between checks.
+        let induction = Induction::from_loop(self, l);
+        let iv = induction.basic.iter().find(|iv| args.contains(&iv.var))?;
+        let doms = DominatorTree::from_cfg(self);
+        if iv.def.0 == l.header || !l.latches.iter().all(|b| doms.dom[&iv.def.0].contains(b)) {
+            return None;
+        }
+
+        // A constant only enters the loop if it is defined on every path there, so the parameters
+        // count as definitions.
+        let with_params = self.with_param_defs();
+        let mut solved: ShimmedCfg<ReachingDefinitions> = ShimmedCfg::from_cfg(&with_params);
+        solved.solve_bit_vector();
+        let outside: Vec<usize> = header
+            .pred
+            .iter()
+            .copied()
+            .filter(|p| !l.body.contains(p))
+            .collect();
+        if l.header == 0 || outside.is_empty() {
+            return None;
+        }
+        let defined_in_loop = |var: &String| {
+            l.body
+                .iter()
+                .flat_map(|&b| &self.block(b).instrs)
+                .any(|insn| dest(insn) == Some(var))
+        };
+        let entering = |var: &String| {
+            let values: Vec<Option<Literal>> = outside
+                .iter()
+                .map(|&p| with_params.constant_out(&solved, p, var))
+                .collect();
+            let first = values[0].clone()?;
+            values
+                .iter()
+                .all(|v| v.as_ref() == Some(&first))
+                .then_some(first)
+        };
+
+        let Literal::Int(step) = entering(&iv.step)? else {
+            return None;
+        };
+        let Literal::Int(mut i) = entering(&iv.var)? else {
+            return None;
+        };
+        let mut operands = vec![];
+        for arg in args {
+            if *arg == iv.var {
+                operands.push(None);
+            } else if defined_in_loop(arg) {
+                return None;
+            } else {
+                operands.push(Some(entering(arg)?));
+            }
+        }
+
+        for trips in 0..=FULL_UNROLL_LIMIT {
+            let values: Vec<Literal> = operands
+                .iter()
+                .map(|v| v.clone().unwrap_or(Literal::Int(i)))
+                .collect();
+            match fold(op, &values) {
+                Some(Literal::Bool(b)) if b == stay_when => {}
+                Some(Literal::Bool(_)) => return Some(trips),
+                _ => return None,
+            }
+            i = if iv.negate {
+                i.wrapping_sub(step)
+            } else {
+                i.wrapping_add(step)
+            };
+        }
+        None
+    }
+
+    /// Variables which are only mentioned in a single block of `l`, and are always written there
+    /// before being read.
+    fn block_locals(&self, l: &NaturalLoop) -> HashMap<usize, Vec<String>> {
+        let args: HashSet<&String> = self
+            .original_function
+            .args
+            .iter()
+            .map(|a| &a.name)
+            .collect();
+        let mut blocks: HashMap<&String, HashSet<usize>> = HashMap::new();
+        let mut written_first: HashMap<&String, bool> = HashMap::new();
+        for block in &self.blocks {
+            for insn in &block.instrs {
+                let (written, read): (Option<&String>, &[String]) = match insn {
+                    Instruction::Constant { dest, .. } => (Some(dest), &[]),
+                    Instruction::Value { dest, args, .. } => (Some(dest), args),
+                    Instruction::Effect { args, .. } => (None, args),
+                    Instruction::Label { .. } => (None, &[]),
+                };
+                for var in read.iter().chain(written) {
+                    blocks.entry(var).or_default().insert(block.start);
+                    written_first
+                        .entry(var)
+                        .or_insert(written == Some(var) && !read.contains(var));
+                }
+            }
+        }
+        let mut locals: HashMap<usize, Vec<String>> = HashMap::new();
+        for (var, in_blocks) in blocks {
+            if let [b] = in_blocks.iter().copied().collect::<Vec<_>>()[..]
+                && l.body.contains(&b)
+                && written_first[var]
+                && !args.contains(var)
+            {
+                locals.entry(b).or_default().push(var.clone());
+            }
+        }
+        locals
+    }
+
+    /// Replaces `l` by `copies` copies of it, as described in `unroll`.
+    fn unroll_loop(&mut self, l: &NaturalLoop, copies: usize, full: bool) {
+        // Every block the copies can jump to needs a label.
+        let mut targets: BTreeSet<usize> = l.body.clone();
+        targets.extend(l.body.iter().flat_map(|&b| self.block(b).flows_to.clone()));
+        for &t in &targets {
+            self.label(t);
+        }
+        let names: HashMap<usize, String> = self
+            .blocks
+            .iter()
+            .filter_map(|b| b.name.clone().map(|n| (b.start, n)))
+            .collect();
+        let block_of = names.iter().map(|(b, n)| (n.clone(), *b)).collect();
+
+        let mut taken_labels = HashSet::new();
+        let mut taken_vars = HashSet::new();
+        let locals = self.block_locals(l);
+        let mut labels = vec![];
+        let mut renames = vec![];
+        for m in 0..copies {
+            let mut copy_labels = HashMap::new();
+            let mut copy_renames = HashMap::new();
+            for &b in &l.body {
+                let name = self.block(b).name.clone().unwrap();
+                if m == 0 {
+                    copy_labels.insert(b, name);
+                    continue;
+                }
+                let mut label = self.fresh_label(&format!("{name}.u{m}"));
+                while !taken_labels.insert(label.clone()) {
+                    label = self.fresh_label(&format!("{label}.u{m}"));
+                }
+                copy_labels.insert(b, label);
+                for var in locals.get(&b).into_iter().flatten() {
+                    let mut new = self.fresh_var(&format!("{var}.u{m}"));
+                    while !taken_vars.insert(new.clone()) {
+                        new = self.fresh_var(&format!("{new}.u{m}"));
+                    }
+                    copy_renames.insert(var.clone(), new);
+                }
+            }
+            labels.push(copy_labels);
+            renames.push(copy_renames);
+        }
+        let unrolling = Unrolling {
+            l,
+            labels,
+            renames,
+            block_of,
+            names,
+            full,
+        };
+
+        // Each copy is laid out like the loop, but starting at its header, so a block which fell
+        // into the header falls into the next copy's header instead.
+        let layout: Vec<usize> = self
+            .blocks
+            .iter()
+            .map(|b| b.start)
+            .filter(|b| l.body.contains(b))
+            .collect();
+        let at_header = layout.iter().position(|&b| b == l.header).unwrap();
+        let order: Vec<usize> = layout[at_header..]
+            .iter()
+            .chain(&layout[..at_header])
+            .copied()
+            .collect();
+        let mut tail: Vec<(bool, Vec<Instruction>)> = vec![];
+        for m in 1..copies {
+            for &b in &order {
+                // The last copy of a fully unrolled loop never gets past its header.
+                if full && m + 1 == copies && b != l.header {
+                    continue;
+                }
+                tail.push((true, unrolling.copy(m, self.block(b))));
+            }
+        }
+
+        // The copies go between the header and a loop block laid out before it, whose back edge
+        // then falls through both ways, or otherwise right after the last block of the loop. The
+        // blocks which used to follow the loop stay after it, so nothing has to jump around the
+        // copies.
+        let header_pos = self
+            .blocks
+            .iter()
+            .position(|b| b.start == l.header)
+            .unwrap();
+        let before_header = header_pos > 0 && l.body.contains(&self.blocks[header_pos - 1].start);
+        let last = *layout.last().unwrap();
+        let mut blocks: Vec<(bool, Vec<Instruction>)> = vec![];
+        for block in &self.blocks {
+            if before_header && block.start == l.header {
+                blocks.append(&mut tail);
+            }
+            if l.body.contains(&block.start) {
+                blocks.push((true, unrolling.copy(0, block)));
+            } else {
+                blocks.push((false, block.instrs.clone()));
+            }
+            if !before_header && block.start == last {
+                blocks.append(&mut tail);
+            }
+        }
+        for i in 0..blocks.len().saturating_sub(1) {
+            let next = match blocks[i + 1].1.first() {
+                Some(Instruction::Label { label, .. }) => label.clone(),
+                _ => continue,
+            };
+            let (copied, block) = &mut blocks[i];
+            if *copied
+                && let Some(Instruction::Effect {
+                    op: EffectOp::Jmp,
+                    labels,
+                    ..
+                }) = block.last()
+                && labels[0] == next
+            {
+                block.pop();
+            }
+        }
+        let instrs = blocks.into_iter().flat_map(|(_, block)| block).collect();
+
+        let mut fun = self.function();
+        fun.instrs = instrs;
+        *self = Cfg::from_function(&fun);
+    }
+}
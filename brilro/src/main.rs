@@ -1,9 +1,16 @@
 use brilro::{
     cfg::{
         analysis::{BasicBlock, Cfg},
-        data_flow::{ReachingDefinitions, ShimmedCfg},
+        call_graph::{self, CallGraph},
+        chains::Chains,
+        data_flow::{AvailableExpressions, Flow, ReachingDefinitions, ShimmedCfg},
+        dce,
         dominator::DominatorTree,
-        ssa,
+        gvn, inline, ipcp,
+        loops::LoopForest,
+        purity::Purity,
+        sccp,
+        ssa::{self, PhiPlacement, SsaStyle},
     },
     parser::ast::Program,
     spec::{self, Trace},
@@ -24,10 +31,30 @@ enum Mode {
     Dce,
     Lvn,
     LvnDce,
+    Gcse,
+    CopyProp,
     ReachingDefs,
+    AvailableExprs,
+    DefUse,
     Dominator,
+    Loops,
+    LoopSimplify,
+    Licm,
+    StrengthReduce,
+    Unroll,
+    Inline,
+    CallGraph,
+    UnreachableFns,
+    Purity,
+    TailCall,
+    Ipcp,
     ToSsa,
     FromSsa,
+    SsaStats,
+    CheckSsa,
+    Sccp,
+    Gvn,
+    SsaDce,
     Spec,
 }
 
@@ -41,10 +68,30 @@ impl FromStr for Mode {
             "dce" => Ok(Mode::Dce),
             "lvn" => Ok(Mode::Lvn),
             "lvn-dce" => Ok(Mode::LvnDce),
+            "gcse" => Ok(Mode::Gcse),
+            "copy-prop" => Ok(Mode::CopyProp),
             "reaching-defs" => Ok(Mode::ReachingDefs),
+            "available-exprs" => Ok(Mode::AvailableExprs),
+            "def-use" => Ok(Mode::DefUse),
             "dom" => Ok(Mode::Dominator),
+            "loops" => Ok(Mode::Loops),
+            "loop-simplify" => Ok(Mode::LoopSimplify),
+            "licm" => Ok(Mode::Licm),
+            "strength-reduce" => Ok(Mode::StrengthReduce),
+            "unroll" => Ok(Mode::Unroll),
+            "inline" => Ok(Mode::Inline),
+            "call-graph" => Ok(Mode::CallGraph),
+            "unreachable-fns" => Ok(Mode::UnreachableFns),
+            "purity" => Ok(Mode::Purity),
+            "tail-call" => Ok(Mode::TailCall),
+            "ipcp" => Ok(Mode::Ipcp),
             "to-ssa" => Ok(Mode::ToSsa),
             "from-ssa" => Ok(Mode::FromSsa),
+            "ssa-stats" => Ok(Mode::SsaStats),
+            "check-ssa" => Ok(Mode::CheckSsa),
+            "sccp" => Ok(Mode::Sccp),
+            "gvn" => Ok(Mode::Gvn),
+            "ssa-dce" => Ok(Mode::SsaDce),
             "spec" => Ok(Mode::Spec),
             _ => Err("unrecognized mode".to_string()),
         }
@@ -59,7 +106,10 @@ impl FromStr for Mode {
 /// language and do various compiler optimizations.
 struct Request {
     /// select what to do with the program, one of: "cfg", "rotate", "dce", "lvn", "lvn-dce",
-    /// "reading-defs", "to-ssa", "spec"
+    /// "gcse", "copy-prop", "reading-defs", "available-exprs", "def-use", "loops",
+    /// "loop-simplify", "licm", "strength-reduce", "unroll", "inline", "call-graph",
+    /// "unreachable-fns", "purity", "tail-call", "ipcp", "to-ssa", "from-ssa", "ssa-stats",
+    /// "check-ssa", "sccp", "gvn", "ssa-dce", "spec"
     #[argh(option, short = 'm')]
     mode: Mode,
 
@@ -70,6 +120,35 @@ struct Request {
     /// use the given trace file
     #[argh(option, short = 't')]
     trace_file: Option<String>,
+
+    /// how many copies of each loop body the unroll mode makes, 4 by default
+    #[argh(option, default = "4")]
+    unroll_factor: usize,
+
+    /// have strength-reduce make every reduction it safely can, rather than only those leaving
+    /// fewer instructions in the loop
+    #[argh(switch)]
+    reduce_all: bool,
+
+    /// how to-ssa merges values where control flow joins, "phi" or "shadow" for `set` and `get`,
+    /// "shadow" by default
+    #[argh(option, default = "SsaStyle::Shadow")]
+    ssa_style: SsaStyle,
+
+    /// which blocks to-ssa merges a variable in, "minimal", "semi-pruned" for variables read
+    /// across blocks, or "pruned" for blocks where it is live, "minimal" by default
+    #[argh(option, default = "PhiPlacement::Minimal")]
+    phi_placement: PhiPlacement,
+
+    /// have reaching-defs and available-exprs print the function as Bril text with the result
+    /// before and after every instruction instead of only each block's outset
+    #[argh(switch)]
+    annotate: bool,
+
+    /// have reaching-defs and available-exprs solve on dense bit vectors, which gives the same result
+    /// faster
+    #[argh(switch)]
+    bit_vector: bool,
 }
 
 fn main() -> ExitCode {
@@ -99,15 +178,89 @@ fn main() -> ExitCode {
         Mode::Cfg => run_cfg(prog, cfg_fun),
         Mode::Rotate => run_rotate(prog),
         Mode::Dce => run_dce(prog),
-        Mode::Lvn => run_opt(prog, BasicBlock::lvn),
+        Mode::Lvn => {
+            let purity = Purity::from_program(&prog);
+            run_opt(prog, |block| block.lvn(&purity))
+        }
         Mode::LvnDce => {
-            apply_to_all_blocks(&mut prog, BasicBlock::lvn);
+            let purity = Purity::from_program(&prog);
+            apply_to_all_blocks(&mut prog, |block| block.lvn(&purity));
             run_dce(prog)
         }
-        Mode::ReachingDefs => run_reaching_defs(prog, cfg_fun),
+        Mode::Gcse => {
+            for fun in prog.functions.iter_mut() {
+                let mut cfg = Cfg::from_function(fun);
+                cfg.gcse();
+                *fun = cfg.function();
+            }
+            run_dce(prog)
+        }
+        Mode::CopyProp => {
+            for fun in prog.functions.iter_mut() {
+                let mut cfg = Cfg::from_function(fun);
+                cfg.copy_propagation();
+                *fun = cfg.function();
+            }
+            run_dce(prog)
+        }
+        Mode::ReachingDefs => {
+            let solve: fn(&mut ShimmedCfg<ReachingDefinitions>) = if req.bit_vector {
+                ShimmedCfg::solve_bit_vector
+            } else {
+                ShimmedCfg::solve
+            };
+            run_data_flow(prog, cfg_fun, req.annotate, solve)
+        }
+        Mode::AvailableExprs => {
+            let solve: fn(&mut ShimmedCfg<AvailableExpressions>) = if req.bit_vector {
+                ShimmedCfg::solve_bit_vector
+            } else {
+                ShimmedCfg::solve
+            };
+            run_data_flow(prog, cfg_fun, req.annotate, solve)
+        }
+        Mode::DefUse => run_def_use(prog, cfg_fun),
         Mode::Dominator => run_dom(prog, cfg_fun),
-        Mode::ToSsa => run_to_ssa(prog),
+        Mode::Loops => run_loops(prog),
+        Mode::LoopSimplify => run_cfg_opt(prog, Cfg::loop_simplify),
+        Mode::Licm => run_cfg_opt(prog, Cfg::licm),
+        Mode::Unroll => run_cfg_opt(prog, |cfg| cfg.unroll(req.unroll_factor)),
+        Mode::Inline => run_inline(prog),
+        Mode::CallGraph => {
+            println!("{}", CallGraph::from_program(&prog).as_dot());
+            Ok(ExitCode::SUCCESS)
+        }
+        Mode::UnreachableFns => {
+            call_graph::eliminate_unreachable(&mut prog);
+            println!("{}", serde_json::to_string_pretty(&prog).unwrap());
+            Ok(ExitCode::SUCCESS)
+        }
+        Mode::StrengthReduce => {
+            for fun in prog.functions.iter_mut() {
+                let mut cfg = Cfg::from_function(fun);
+                cfg.strength_reduce(req.reduce_all);
+                cfg.copy_propagation();
+                *fun = cfg.function();
+            }
+            run_dce(prog)
+        }
+        Mode::Purity => run_purity(prog),
+        Mode::TailCall => run_cfg_opt(prog, Cfg::tail_call_elimination),
+        Mode::Ipcp => {
+            ipcp::propagate_constant_args(&mut prog);
+            println!("{}", serde_json::to_string_pretty(&prog).unwrap());
+            Ok(ExitCode::SUCCESS)
+        }
+        Mode::ToSsa => run_to_ssa(prog, req.ssa_style, req.phi_placement),
         Mode::FromSsa => run_from_ssa(prog),
+        Mode::SsaStats => run_ssa_stats(prog),
+        Mode::CheckSsa => run_check_ssa(prog),
+        Mode::Sccp => run_ssa_opt(prog, sccp::sccp),
+        Mode::Gvn => run_ssa_opt(prog, gvn::gvn),
+        Mode::SsaDce => {
+            let purity = Purity::from_program(&prog);
+            run_ssa_opt(prog, |cfg| dce::ssa_dce(cfg, &purity))
+        }
         Mode::Spec => run_spec(prog, req.trace_file),
     };
 
@@ -162,10 +315,20 @@ fn run_spec(mut prog: Program, trace_file: Option<String>) -> Result<ExitCode, S
     Ok(ExitCode::SUCCESS)
 }
 
-fn run_to_ssa(mut prog: Program) -> Result<ExitCode, String> {
+fn run_inline(mut prog: Program) -> Result<ExitCode, String> {
+    inline::inline(&mut prog);
+    println!("{}", serde_json::to_string_pretty(&prog).unwrap());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_to_ssa(
+    mut prog: Program,
+    style: SsaStyle,
+    placement: PhiPlacement,
+) -> Result<ExitCode, String> {
     for f in &mut prog.functions {
         let cfg = Cfg::from_function(f);
-        let (cfg, args) = ssa::to_ssa(&cfg, f);
+        let (cfg, args) = ssa::to_ssa(&cfg, f, style, placement);
         *f = cfg.function();
         f.args = args;
     }
@@ -173,6 +336,40 @@ fn run_to_ssa(mut prog: Program) -> Result<ExitCode, String> {
     Ok(ExitCode::SUCCESS)
 }
 
+fn run_ssa_stats(prog: Program) -> Result<ExitCode, String> {
+    let stats: Vec<_> = prog
+        .functions
+        .iter()
+        .map(|f| {
+            let cfg = Cfg::from_function(f);
+            serde_json::json!({
+                "function": f.name,
+                "minimal": ssa::count_gets(&cfg, f, PhiPlacement::Minimal),
+                "semi-pruned": ssa::count_gets(&cfg, f, PhiPlacement::SemiPruned),
+                "pruned": ssa::count_gets(&cfg, f, PhiPlacement::Pruned),
+            })
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_check_ssa(prog: Program) -> Result<ExitCode, String> {
+    let mut violations = 0;
+    for f in &prog.functions {
+        for error in ssa::verify(&Cfg::from_function(f)) {
+            println!("@{}: {error}", f.name);
+            violations += 1;
+        }
+    }
+    if violations == 0 {
+        println!("ssa ok");
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Err(format!("{violations} ssa violations"))
+    }
+}
+
 fn run_from_ssa(mut prog: Program) -> Result<ExitCode, String> {
     for f in &mut prog.functions {
         let cfg = Cfg::from_function(f);
@@ -183,6 +380,32 @@ fn run_from_ssa(mut prog: Program) -> Result<ExitCode, String> {
     Ok(ExitCode::SUCCESS)
 }
 
+fn run_cfg_opt<F>(mut prog: Program, opt: F) -> Result<ExitCode, String>
+where
+    F: Fn(&mut Cfg),
+{
+    for f in &mut prog.functions {
+        let mut cfg = Cfg::from_function(f);
+        opt(&mut cfg);
+        *f = cfg.function();
+    }
+    println!("{}", serde_json::to_string_pretty(&prog).unwrap());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_ssa_opt<F>(mut prog: Program, opt: F) -> Result<ExitCode, String>
+where
+    F: Fn(&Cfg) -> Cfg,
+{
+    for f in &mut prog.functions {
+        let cfg = Cfg::from_function(f);
+        let cfg = opt(&cfg);
+        *f = cfg.function();
+    }
+    println!("{}", serde_json::to_string_pretty(&prog).unwrap());
+    Ok(ExitCode::SUCCESS)
+}
+
 fn run_dom(prog: Program, cfg_fun: String) -> Result<ExitCode, String> {
     let cfg = get_cfg(prog, cfg_fun)?;
     let dom = DominatorTree::from_cfg(&cfg);
@@ -194,18 +417,58 @@ fn run_dom(prog: Program, cfg_fun: String) -> Result<ExitCode, String> {
     }
 }
 
-fn run_reaching_defs(prog: Program, cfg_fun: String) -> Result<ExitCode, String> {
+fn run_loops(prog: Program) -> Result<ExitCode, String> {
+    let loops: Vec<_> = prog
+        .functions
+        .iter()
+        .map(|f| {
+            let forest = LoopForest::from_cfg(&Cfg::from_function(f));
+            serde_json::json!({ "function": f.name, "loops": forest.loops })
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&loops).unwrap());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_purity(prog: Program) -> Result<ExitCode, String> {
+    let purity = Purity::from_program(&prog);
+    let classes: Vec<_> = prog
+        .functions
+        .iter()
+        .map(|f| serde_json::json!({ "function": f.name, "pure": purity.is_pure(&f.name) }))
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&classes).unwrap());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_data_flow<T: Flow>(
+    prog: Program,
+    cfg_fun: String,
+    annotate: bool,
+    solve: fn(&mut ShimmedCfg<T>),
+) -> Result<ExitCode, String> {
+    let cfg = get_cfg(prog, cfg_fun)?;
+    let mut shimmed: ShimmedCfg<T> = ShimmedCfg::from_cfg(&cfg);
+    solve(&mut shimmed);
+    if annotate {
+        shimmed.print_annotated(&cfg);
+    } else {
+        shimmed.print_outsets();
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_def_use(prog: Program, cfg_fun: String) -> Result<ExitCode, String> {
     let cfg = get_cfg(prog, cfg_fun)?;
-    let mut shimmed: ShimmedCfg<ReachingDefinitions> = ShimmedCfg::from_cfg(&cfg);
-    shimmed.solve();
-    shimmed.print_outsets();
+    Chains::from_reaching_definitions(&cfg).print(&cfg);
     Ok(ExitCode::SUCCESS)
 }
 
 fn run_dce(mut prog: Program) -> Result<ExitCode, String> {
+    let purity = Purity::from_program(&prog);
     for fun in prog.functions.iter_mut() {
         let mut cfg = Cfg::from_function(fun);
-        cfg.dce();
+        cfg.dce(&purity);
         *fun = cfg.function();
     }
     let mutated_prog = serde_json::to_string_pretty(&prog).unwrap();
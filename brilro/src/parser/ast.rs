@@ -1,3 +1,5 @@
+use std::fmt::Display;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -57,7 +59,7 @@ pub enum EffectOp {
     Guard,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ValueOp {
     Add,
@@ -76,6 +78,7 @@ pub enum ValueOp {
     Id,
     Get,
     Undef,
+    Phi,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
@@ -142,3 +145,67 @@ pub struct Span {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub src: Option<String>,
 }
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+impl Display for Literal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Literal::Int(i) => write!(f, "{i}"),
+            Literal::Bool(b) => write!(f, "{b}"),
+        }
+    }
+}
+
+/// The operation with its `@funcs`, arguments and `.labels` in the order bril2txt prints them.
+fn operation(op: String, args: &[String], funcs: &[String], labels: &[String]) -> String {
+    let funcs = funcs.iter().map(|f| format!("@{f}"));
+    let labels = labels.iter().map(|l| format!(".{l}"));
+    std::iter::once(op)
+        .chain(funcs)
+        .chain(args.iter().cloned())
+        .chain(labels)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Prints the instruction as a line of Bril text, without indentation.
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Constant {
+                dest, ty, value, ..
+            } => write!(f, "{dest}: {ty} = const {value};"),
+            Instruction::Value {
+                op,
+                dest,
+                ty,
+                args,
+                funcs,
+                labels,
+                ..
+            } => {
+                let op = format!("{op:?}").to_lowercase();
+                write!(f, "{dest}: {ty} = {};", operation(op, args, funcs, labels))
+            }
+            Instruction::Effect {
+                op,
+                args,
+                funcs,
+                labels,
+                ..
+            } => {
+                let op = format!("{op:?}").to_lowercase();
+                write!(f, "{};", operation(op, args, funcs, labels))
+            }
+            Instruction::Label { label, .. } => write!(f, ".{label}:"),
+        }
+    }
+}